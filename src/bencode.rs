@@ -0,0 +1,428 @@
+//! Byte oriented bencode values
+//!
+//! Unlike serde_bencode this keeps every value around, including keys we don't model,
+//! so it can be used to inspect arbitrary torrents and tracker responses.
+use std::{collections::BTreeMap, ops::Range};
+use thiserror::Error;
+
+/// Nesting deeper than this is rejected so hostile input can't blow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    /// i42e
+    Int(i64),
+    /// 4:spam, may hold raw binary such as the `pieces` string
+    Bytes(Vec<u8>),
+    /// l4:spami42ee
+    List(Vec<BencodeValue>),
+    /// d3:cow3:mooe, keys are byte strings
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BencodeError {
+    #[error("unexpected end of input at byte {0}")]
+    UnexpectedEof(usize),
+    #[error("unexpected byte {byte:#04x} at byte {offset}")]
+    UnexpectedByte { byte: u8, offset: usize },
+    #[error("invalid integer at byte {0}")]
+    InvalidInteger(usize),
+    #[error("invalid string length at byte {0}")]
+    InvalidLength(usize),
+    #[error("nesting too deep at byte {0}")]
+    TooDeep(usize),
+    #[error("trailing data at byte {0}")]
+    TrailingData(usize),
+}
+
+impl BencodeError {
+    /// Byte offset into the input where decoding failed
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::UnexpectedEof(offset)
+            | Self::UnexpectedByte { offset, .. }
+            | Self::InvalidInteger(offset)
+            | Self::InvalidLength(offset)
+            | Self::TooDeep(offset)
+            | Self::TrailingData(offset) => *offset,
+        }
+    }
+}
+
+impl BencodeValue {
+    /// Decodes a complete bencoded value, anything left over is an error
+    pub fn decode(input: &[u8]) -> Result<BencodeValue, BencodeError> {
+        let (value, consumed) = Self::decode_prefix(input)?;
+        if consumed != input.len() {
+            return Err(BencodeError::TrailingData(consumed));
+        }
+        Ok(value)
+    }
+
+    /// Decodes the first value in `input` and returns it with the number of bytes it used
+    pub fn decode_prefix(input: &[u8]) -> Result<(BencodeValue, usize), BencodeError> {
+        let mut parser = Parser { input, pos: 0 };
+        let value = parser.parse_value(0)?;
+        Ok((value, parser.pos))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Self::Bytes(bytes) => encode_bytes(bytes, out),
+            Self::List(list) => {
+                out.push(b'l');
+                list.iter().for_each(|value| value.encode_into(out));
+                out.push(b'e');
+            }
+            Self::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// JSON view of the value, byte strings that aren't UTF-8 are rendered as hex
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Int(i) => (*i).into(),
+            Self::Bytes(bytes) => bytes_to_json_string(bytes).into(),
+            Self::List(list) => list.iter().map(|value| value.to_json()).collect(),
+            Self::Dict(dict) => dict
+                .iter()
+                .map(|(key, value)| (bytes_to_json_string(key), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            Self::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            Self::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        self.as_dict().and_then(|dict| dict.get(key.as_bytes()))
+    }
+}
+
+/// Byte range of the value stored under `key` in the top level dictionary of `input`
+///
+/// Used to hash the info dictionary exactly as it appeared on disk.
+pub fn dict_value_span(input: &[u8], key: &[u8]) -> Result<Option<Range<usize>>, BencodeError> {
+    let mut parser = Parser { input, pos: 0 };
+    match parser.peek()? {
+        b'd' => parser.pos += 1,
+        byte => return Err(BencodeError::UnexpectedByte { byte, offset: 0 }),
+    }
+    while parser.peek()? != b'e' {
+        let entry_key = parser.parse_bytes()?;
+        let start = parser.pos;
+        parser.parse_value(1)?;
+        if entry_key == key {
+            return Ok(Some(start..parser.pos));
+        }
+    }
+    Ok(None)
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+fn bytes_to_json_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => hex::encode(bytes),
+    }
+}
+
+/// Lets BencodeValue sit inside serde_bencode structs, e.g. to keep keys we don't model
+mod serde_impl {
+    use super::BencodeValue;
+    use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+    use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+    use std::collections::BTreeMap;
+
+    struct IBencodeValue;
+
+    impl<'de> Visitor<'de> for IBencodeValue {
+        type Value = BencodeValue;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "a bencoded integer, byte string, list or dictionary")
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+            Ok(BencodeValue::Int(v))
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            i64::try_from(v)
+                .map(BencodeValue::Int)
+                .map_err(|_| E::custom("integer out of range"))
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(BencodeValue::Bytes(v.to_vec()))
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(BencodeValue::Bytes(v))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(BencodeValue::Bytes(v.as_bytes().to_vec()))
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut list = Vec::new();
+            while let Some(value) = seq.next_element()? {
+                list.push(value);
+            }
+            Ok(BencodeValue::List(list))
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut dict = BTreeMap::new();
+            while let Some((key, value)) = map.next_entry::<serde_bytes::ByteBuf, _>()? {
+                dict.insert(key.into_vec(), value);
+            }
+            Ok(BencodeValue::Dict(dict))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for BencodeValue {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(IBencodeValue)
+        }
+    }
+
+    impl Serialize for BencodeValue {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match self {
+                BencodeValue::Int(i) => serializer.serialize_i64(*i),
+                BencodeValue::Bytes(bytes) => serializer.serialize_bytes(bytes),
+                BencodeValue::List(list) => {
+                    let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                    for value in list {
+                        seq.serialize_element(value)?;
+                    }
+                    seq.end()
+                }
+                BencodeValue::Dict(dict) => {
+                    let mut map = serializer.serialize_map(Some(dict.len()))?;
+                    for (key, value) in dict {
+                        map.serialize_entry(serde_bytes::Bytes::new(key), value)?;
+                    }
+                    map.end()
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or(BencodeError::UnexpectedEof(self.pos))
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<BencodeValue, BencodeError> {
+        if depth > MAX_DEPTH {
+            return Err(BencodeError::TooDeep(self.pos));
+        }
+        match self.peek()? {
+            b'i' => self.parse_int().map(BencodeValue::Int),
+            b'0'..=b'9' => self.parse_bytes().map(BencodeValue::Bytes),
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.parse_value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                loop {
+                    match self.peek()? {
+                        b'e' => break,
+                        b'0'..=b'9' => {
+                            let key = self.parse_bytes()?;
+                            let value = self.parse_value(depth + 1)?;
+                            dict.insert(key, value);
+                        }
+                        byte => {
+                            return Err(BencodeError::UnexpectedByte {
+                                byte,
+                                offset: self.pos,
+                            })
+                        }
+                    }
+                }
+                self.pos += 1;
+                Ok(BencodeValue::Dict(dict))
+            }
+            byte => Err(BencodeError::UnexpectedByte {
+                byte,
+                offset: self.pos,
+            }),
+        }
+    }
+
+    /// i<digits>e, no leading zeros and no negative zero
+    fn parse_int(&mut self) -> Result<i64, BencodeError> {
+        let start = self.pos;
+        self.pos += 1;
+        let end = self.input[self.pos..]
+            .iter()
+            .position(|&b| b == b'e')
+            .map(|i| self.pos + i)
+            .ok_or(BencodeError::UnexpectedEof(self.input.len()))?;
+        let digits = &self.input[self.pos..end];
+        let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+        let valid = !unsigned.is_empty()
+            && unsigned.iter().all(u8::is_ascii_digit)
+            && !(unsigned[0] == b'0' && (unsigned.len() > 1 || digits.len() != unsigned.len()));
+        if !valid {
+            return Err(BencodeError::InvalidInteger(start));
+        }
+        let value = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(BencodeError::InvalidInteger(start))?;
+        self.pos = end + 1;
+        Ok(value)
+    }
+
+    /// <length>:<bytes>
+    fn parse_bytes(&mut self) -> Result<Vec<u8>, BencodeError> {
+        let start = self.pos;
+        let colon = self.input[self.pos..]
+            .iter()
+            .position(|&b| b == b':')
+            .map(|i| self.pos + i)
+            .ok_or(BencodeError::UnexpectedEof(self.input.len()))?;
+        let digits = &self.input[self.pos..colon];
+        if !digits.iter().all(u8::is_ascii_digit) || (digits.len() > 1 && digits[0] == b'0') {
+            return Err(BencodeError::InvalidLength(start));
+        }
+        let length = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or(BencodeError::InvalidLength(start))?;
+        let data_start = colon + 1;
+        let data_end = data_start
+            .checked_add(length)
+            .filter(|&end| end <= self.input.len())
+            .ok_or(BencodeError::UnexpectedEof(self.input.len()))?;
+        self.pos = data_end;
+        Ok(self.input[data_start..data_end].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_to_json() {
+        let value = BencodeValue::decode(b"d3:cow3:moo4:spaml1:ai-42eee").expect("Decoding failed");
+        assert_eq!(value.to_json().to_string(), r#"{"cow":"moo","spam":["a",-42]}"#);
+    }
+
+    #[test]
+    fn test_binary_string_round_trip() {
+        let mut input = b"d6:pieces4:".to_vec();
+        input.extend_from_slice(&[0xff, 0x00, 0xfe, 0x80]);
+        input.push(b'e');
+        let value = BencodeValue::decode(&input).expect("Decoding failed");
+        assert_eq!(value.get("pieces").and_then(|v| v.as_bytes()), Some(&[0xff, 0x00, 0xfe, 0x80][..]));
+        assert_eq!(value.to_json().to_string(), r#"{"pieces":"ff00fe80"}"#);
+        assert_eq!(value.encode(), input);
+    }
+
+    #[test]
+    fn test_errors_carry_offset() {
+        assert_eq!(BencodeValue::decode(b"l4:spam"), Err(BencodeError::UnexpectedEof(7)));
+        assert_eq!(BencodeValue::decode(b"li03ee"), Err(BencodeError::InvalidInteger(1)));
+        assert_eq!(BencodeValue::decode(b"i-0e"), Err(BencodeError::InvalidInteger(0)));
+        assert_eq!(BencodeValue::decode(b"5:abc"), Err(BencodeError::UnexpectedEof(5)));
+        assert_eq!(BencodeValue::decode(b"i1ei2e"), Err(BencodeError::TrailingData(3)));
+        assert_eq!(
+            BencodeValue::decode(b"di1e1:ae"),
+            Err(BencodeError::UnexpectedByte { byte: b'i', offset: 1 })
+        );
+        assert_eq!(BencodeValue::decode(b"x").unwrap_err().offset(), 0);
+    }
+}
@@ -0,0 +1,98 @@
+//! Which pieces a peer, or we, have
+
+/// One bit per piece, the high bit of the first byte is piece 0
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    num_pieces: usize,
+}
+
+impl Bitfield {
+    /// Bitfield without any piece
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            bits: vec![0; num_pieces.div_ceil(8)],
+            num_pieces,
+        }
+    }
+
+    /// Bitfield with every piece
+    pub fn full(num_pieces: usize) -> Self {
+        let mut bitfield = Self::new(num_pieces);
+        (0..num_pieces).for_each(|piece| bitfield.set(piece));
+        bitfield
+    }
+
+    /// Builds a bitfield from the payload of a `Bitfield` message, spare bits are ignored
+    pub fn from_payload(payload: &[u8], num_pieces: usize) -> Self {
+        let mut bitfield = Self::new(num_pieces);
+        (0..num_pieces)
+            .filter(|piece| payload.get(piece / 8).is_some_and(|byte| byte & (0x80 >> (piece % 8)) != 0))
+            .for_each(|piece| bitfield.set(piece));
+        bitfield
+    }
+
+    pub fn has(&self, piece: usize) -> bool {
+        piece < self.num_pieces && self.bits[piece / 8] & (0x80 >> (piece % 8)) != 0
+    }
+
+    /// Marks a piece as present, out of range pieces are ignored
+    pub fn set(&mut self, piece: usize) {
+        if piece < self.num_pieces {
+            self.bits[piece / 8] |= 0x80 >> (piece % 8);
+        }
+    }
+
+    pub fn unset(&mut self, piece: usize) {
+        if piece < self.num_pieces {
+            self.bits[piece / 8] &= !(0x80 >> (piece % 8));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_pieces == 0
+    }
+
+    /// Number of pieces present
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.num_pieces
+    }
+
+    /// Indices of the pieces present
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(|piece| self.has(*piece))
+    }
+
+    /// Payload of a `Bitfield` message
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitfield_payload() {
+        let bitfield = Bitfield::from_payload(&[0b1010_0000, 0b1111_1111], 10);
+        assert!(bitfield.has(0) && !bitfield.has(1) && bitfield.has(2));
+        assert!(bitfield.has(8) && bitfield.has(9) && !bitfield.has(10));
+        // the spare bits of the last byte are not pieces
+        assert_eq!(bitfield.count(), 4);
+        assert_eq!(bitfield.as_bytes(), &[0b1010_0000, 0b1100_0000]);
+
+        let mut full = Bitfield::full(10);
+        assert!(full.is_complete());
+        full.unset(9);
+        assert_eq!(full.pieces().last(), Some(8));
+    }
+}
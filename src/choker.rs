@@ -0,0 +1,199 @@
+//! Deciding which peers we upload to.
+//!
+//! A fixed number of upload slots go to the interested peers that gave us the
+//! most over the last round, or, while seeding, the ones we uploaded the most to.
+//! Rounds are `RECHOKE_INTERVAL` long. On top of that one or more optimistic
+//! slots go to a random interested peer and move on every
+//! `OPTIMISTIC_INTERVAL`, so new peers get a chance to prove themselves.
+
+use rand::seq::IteratorRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::sync::{futures::Notified, Notify};
+
+pub const UPLOAD_SLOTS: usize = 4;
+pub const OPTIMISTIC_SLOTS: usize = 1;
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Choker {
+    upload_slots: usize,
+    optimistic_slots: usize,
+    state: Mutex<ChokerState>,
+    /// woken whenever the set of unchoked peers changes
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct ChokerState {
+    peers: HashMap<SocketAddr, PeerRates>,
+    /// peers holding a regular slot
+    unchoked: HashSet<SocketAddr>,
+    /// peers holding an optimistic slot
+    optimistic: HashSet<SocketAddr>,
+}
+
+/// Bytes exchanged with a peer during the current round
+#[derive(Debug, Default)]
+struct PeerRates {
+    interested: bool,
+    downloaded: usize,
+    uploaded: usize,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new(UPLOAD_SLOTS, OPTIMISTIC_SLOTS)
+    }
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize, optimistic_slots: usize) -> Self {
+        Self {
+            upload_slots,
+            optimistic_slots,
+            state: Mutex::new(ChokerState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn join(&self, peer: SocketAddr) {
+        self.state.lock().expect("choker lock poisoned").peers.entry(peer).or_default();
+    }
+
+    /// The peer left, its slot is handed out at the next round
+    pub fn leave(&self, peer: &SocketAddr) {
+        let mut state = self.state.lock().expect("choker lock poisoned");
+        state.peers.remove(peer);
+        state.unchoked.remove(peer);
+        state.optimistic.remove(peer);
+    }
+
+    /// A peer that becomes interested while a regular slot is free gets it right away
+    pub fn interested(&self, peer: SocketAddr, interested: bool) {
+        let mut state = self.state.lock().expect("choker lock poisoned");
+        state.peers.entry(peer).or_default().interested = interested;
+        if interested
+            && state.unchoked.len() < self.upload_slots
+            && !state.optimistic.contains(&peer)
+            && state.unchoked.insert(peer)
+        {
+            drop(state);
+            self.changed.notify_waiters();
+        }
+    }
+
+    pub fn downloaded(&self, peer: &SocketAddr, bytes: usize) {
+        if let Some(rates) = self.state.lock().expect("choker lock poisoned").peers.get_mut(peer) {
+            rates.downloaded += bytes;
+        }
+    }
+
+    pub fn uploaded(&self, peer: &SocketAddr, bytes: usize) {
+        if let Some(rates) = self.state.lock().expect("choker lock poisoned").peers.get_mut(peer) {
+            rates.uploaded += bytes;
+        }
+    }
+
+    pub fn is_unchoked(&self, peer: &SocketAddr) -> bool {
+        let state = self.state.lock().expect("choker lock poisoned");
+        state.unchoked.contains(peer) || state.optimistic.contains(peer)
+    }
+
+    /// Resolves when peers were choked or unchoked
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+
+    /// Ends a round: the regular slots go to the interested peers with the best rate,
+    /// by what we downloaded from them or, while `seeding`, what we uploaded to them.
+    /// Optimistic slots move to other peers when `rotate_optimistic`, or when theirs lost interest.
+    pub fn rechoke(&self, seeding: bool, rotate_optimistic: bool) {
+        let mut guard = self.state.lock().expect("choker lock poisoned");
+        let state = &mut *guard;
+        let mut ranked: Vec<(&SocketAddr, &PeerRates)> = state.peers.iter().filter(|(_, rates)| rates.interested).collect();
+        ranked.sort_by_key(|(_, rates)| std::cmp::Reverse(if seeding { rates.uploaded } else { rates.downloaded }));
+        let unchoked: HashSet<SocketAddr> = ranked.iter().take(self.upload_slots).map(|(peer, _)| **peer).collect();
+
+        let mut optimistic: HashSet<SocketAddr> = match rotate_optimistic {
+            true => HashSet::new(),
+            false => state
+                .optimistic
+                .iter()
+                .filter(|peer| !unchoked.contains(*peer) && state.peers.get(*peer).is_some_and(|rates| rates.interested))
+                .copied()
+                .collect(),
+        };
+        let candidates = ranked
+            .iter()
+            .map(|(peer, _)| **peer)
+            .filter(|peer| !unchoked.contains(peer) && !optimistic.contains(peer))
+            .choose_multiple(&mut rand::thread_rng(), self.optimistic_slots.saturating_sub(optimistic.len()));
+        optimistic.extend(candidates);
+
+        let changed = unchoked != state.unchoked || optimistic != state.optimistic;
+        state.unchoked = unchoked;
+        state.optimistic = optimistic;
+        state.peers.values_mut().for_each(|rates| {
+            rates.downloaded = 0;
+            rates.uploaded = 0;
+        });
+        drop(guard);
+        if changed {
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Rechokes every `RECHOKE_INTERVAL` and rotates the optimistic slots every
+    /// `OPTIMISTIC_INTERVAL`, until the task is dropped
+    pub async fn run(&self, seeding: impl Fn() -> bool) {
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        let rounds_per_rotation = (OPTIMISTIC_INTERVAL.as_secs() / RECHOKE_INTERVAL.as_secs()).max(1);
+        let mut round: u64 = 0;
+        loop {
+            rechoke.tick().await;
+            self.rechoke(seeding(), round.is_multiple_of(rounds_per_rotation));
+            round += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_follow_rates() {
+        let choker = Choker::new(1, 1);
+        let peers: Vec<SocketAddr> = (1..=4).map(|i| format!("10.0.0.{i}:6881").parse().unwrap()).collect();
+        peers.iter().for_each(|peer| choker.join(*peer));
+        // the first interested peer takes the free slot right away
+        choker.interested(peers[0], true);
+        choker.interested(peers[1], true);
+        choker.interested(peers[2], true);
+        assert!(choker.is_unchoked(&peers[0]) && !choker.is_unchoked(&peers[1]));
+
+        choker.uploaded(&peers[1], 100);
+        choker.downloaded(&peers[2], 100);
+        choker.rechoke(true, true);
+        assert!(choker.is_unchoked(&peers[1]), "Best upload rate while seeding");
+        // the optimistic slot went to one of the others, never to the uninterested peer
+        assert!(choker.is_unchoked(&peers[0]) ^ choker.is_unchoked(&peers[2]));
+        assert!(!choker.is_unchoked(&peers[3]));
+
+        choker.downloaded(&peers[2], 100);
+        choker.rechoke(false, false);
+        assert!(choker.is_unchoked(&peers[2]), "Best download rate while leeching");
+
+        choker.leave(&peers[2]);
+        choker.interested(peers[1], false);
+        choker.rechoke(false, false);
+        assert!(choker.is_unchoked(&peers[0]));
+        assert!(!choker.is_unchoked(&peers[1]) && !choker.is_unchoked(&peers[2]));
+    }
+}
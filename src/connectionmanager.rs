@@ -0,0 +1,352 @@
+//! Deciding which peers to connect to, and when.
+//!
+//! Candidates come from trackers, from connected peers through PEX and from the
+//! addresses peers listen on, which they give in their extension handshake. A torrent
+//! only connects to as many peers as its own limit allows, and every torrent of
+//! the session shares the `ConnectionSlots`: an overall limit on connections and
+//! a smaller one on connections still being set up (half-open). A peer that
+//! cannot be reached is tried again later, waiting twice as long after every
+//! failure, until it failed `MAX_FAILURES` times in a row and is forgotten.
+
+use crate::{
+    handshake::supports_extensions,
+    message::MessageFramer,
+    peerconnection::PeerConnection,
+    utils::exchange_handshake,
+};
+use anyhow::Context;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{futures::Notified, Notify, OwnedSemaphorePermit, Semaphore},
+    time::{timeout, Instant},
+};
+use tokio_util::codec::Framed;
+
+/// Connections of every torrent together unless configured otherwise
+pub const MAX_CONNECTIONS: usize = 200;
+/// Connections being set up at the same time, across every torrent
+pub const MAX_HALF_OPEN: usize = 20;
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Failures in a row after which a peer is forgotten
+pub const MAX_FAILURES: u32 = 5;
+/// Wait after the first failure, it doubles with every further one
+const BACKOFF: Duration = Duration::from_secs(5);
+/// How often a torrent checks for slots another torrent freed
+const SLOT_POLL: Duration = Duration::from_secs(1);
+
+/// Where we heard about a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    /// where a peer we were connected to listens, see `PeerConnection::listen_addrs`
+    Handshake,
+}
+
+/// Connection limits shared by every torrent of a session
+#[derive(Debug, Clone)]
+pub struct ConnectionSlots {
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+}
+
+impl Default for ConnectionSlots {
+    fn default() -> Self {
+        Self::new(MAX_CONNECTIONS, MAX_HALF_OPEN)
+    }
+}
+
+impl ConnectionSlots {
+    pub fn new(connections: usize, half_open: usize) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(connections)),
+            half_open: Arc::new(Semaphore::new(half_open)),
+        }
+    }
+}
+
+/// The peers of one torrent we know about and our connections to them
+#[derive(Debug)]
+pub struct ConnectionManager {
+    slots: ConnectionSlots,
+    max_peers: usize,
+    backoff: Duration,
+    state: Mutex<ManagerState>,
+    /// woken when candidates are added
+    added: Notify,
+}
+
+#[derive(Debug, Default)]
+struct ManagerState {
+    candidates: HashMap<SocketAddr, Candidate>,
+    /// connecting or connected, each holds one of the session's connection slots
+    active: HashMap<SocketAddr, OwnedSemaphorePermit>,
+    /// connecting, each holds a half-open slot until its handshake is done
+    connecting: HashMap<SocketAddr, OwnedSemaphorePermit>,
+    /// failed too often or banned, not taken back from any source
+    forgotten: HashSet<SocketAddr>,
+    /// candidates are tried in the order we heard of them
+    next_order: u64,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: PeerSource,
+    failures: u32,
+    retry_at: Instant,
+    order: u64,
+}
+
+impl ConnectionManager {
+    /// Connects to at most `max_peers` peers, within the limits of `slots`
+    pub fn new(slots: ConnectionSlots, max_peers: usize) -> Self {
+        Self {
+            slots,
+            max_peers,
+            backoff: BACKOFF,
+            state: Mutex::new(ManagerState::default()),
+            added: Notify::new(),
+        }
+    }
+
+    /// Replaces the wait after the first failure
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Peers we already know or forgot are ignored
+    pub fn add(&self, peers: impl IntoIterator<Item = SocketAddr>, source: PeerSource) {
+        let mut state = self.state.lock().expect("manager lock poisoned");
+        let state = &mut *state;
+        let now = Instant::now();
+        let mut added = false;
+        for peer in peers {
+            if state.forgotten.contains(&peer) || state.candidates.contains_key(&peer) {
+                continue;
+            }
+            let order = state.next_order;
+            state.next_order += 1;
+            state.candidates.insert(peer, Candidate { source, failures: 0, retry_at: now, order });
+            added = true;
+        }
+        if added {
+            self.added.notify_one();
+        }
+    }
+
+    /// Resolves when candidates were added since the last call
+    pub fn added(&self) -> Notified<'_> {
+        self.added.notified()
+    }
+
+    pub fn source(&self, peer: &SocketAddr) -> Option<PeerSource> {
+        let state = self.state.lock().expect("manager lock poisoned");
+        state.candidates.get(peer).map(|candidate| candidate.source)
+    }
+
+    /// The candidate to connect to now, if one is due and the limits allow another
+    /// connection. It holds a connection slot and a half-open slot from now on.
+    pub fn next_attempt(&self) -> Option<SocketAddr> {
+        let mut state = self.state.lock().expect("manager lock poisoned");
+        if state.active.len() >= self.max_peers {
+            return None;
+        }
+        let now = Instant::now();
+        let peer = state
+            .candidates
+            .iter()
+            .filter(|(peer, candidate)| candidate.retry_at <= now && !state.active.contains_key(*peer))
+            .min_by_key(|(_, candidate)| (candidate.retry_at, candidate.order))
+            .map(|(peer, _)| *peer)?;
+        let half_open = self.slots.half_open.clone().try_acquire_owned().ok()?;
+        let connection = self.slots.connections.clone().try_acquire_owned().ok()?;
+        state.active.insert(peer, connection);
+        state.connecting.insert(peer, half_open);
+        Some(peer)
+    }
+
+    /// A peer connected to us, it takes a slot like the ones we connect to.
+    /// False when we are connected to it already or no slot is left.
+    pub fn accept(&self, peer: SocketAddr) -> bool {
+        let mut state = self.state.lock().expect("manager lock poisoned");
+        if state.active.len() >= self.max_peers || state.active.contains_key(&peer) {
+            return false;
+        }
+        let Ok(connection) = self.slots.connections.clone().try_acquire_owned() else {
+            return false;
+        };
+        state.active.insert(peer, connection);
+        true
+    }
+
+    /// Connects and handshakes with a peer `next_attempt` returned, each step with its own
+    /// timeout. A failure counts towards the peer being forgotten.
+    pub async fn connect(
+        &self,
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        reserved: [u8; 8],
+        num_of_pieces: usize,
+    ) -> anyhow::Result<PeerConnection> {
+        let res: anyhow::Result<PeerConnection> = async {
+            let mut tcp_stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(peer))
+                .await
+                .context("Connect timed out")?
+                .context("TCP connection to peer")?;
+            let handshake = timeout(HANDSHAKE_TIMEOUT, exchange_handshake(&mut tcp_stream, info_hash, reserved))
+                .await
+                .context("Handshake timed out")??;
+            let mut conn = PeerConnection::new(Framed::new(tcp_stream, MessageFramer), num_of_pieces);
+            conn.extensions = supports_extensions(&reserved) && supports_extensions(&handshake.reserved);
+            Ok(conn)
+        }
+        .await;
+        let mut state = self.state.lock().expect("manager lock poisoned");
+        state.connecting.remove(&peer);
+        if res.is_err() {
+            self.failed(&mut state, peer);
+        }
+        res.with_context(|| format!("Connect to {peer}"))
+    }
+
+    /// The connection ended, a peer that left in good terms may be connected to again later.
+    /// Its slot is free from now on.
+    pub fn closed(&self, peer: SocketAddr, clean: bool) {
+        let mut state = self.state.lock().expect("manager lock poisoned");
+        state.active.remove(&peer);
+        match clean {
+            true => {
+                if let Some(candidate) = state.candidates.get_mut(&peer) {
+                    candidate.failures = 0;
+                    candidate.retry_at = Instant::now() + self.backoff;
+                }
+            }
+            false => self.failed(&mut state, peer),
+        }
+    }
+
+    /// Never connects to `peer` again, e.g. after it was banned
+    pub fn forget(&self, peer: SocketAddr) {
+        let mut state = self.state.lock().expect("manager lock poisoned");
+        state.candidates.remove(&peer);
+        state.forgotten.insert(peer);
+    }
+
+    /// When the next candidate is due, None while this torrent is at its limit or has no
+    /// candidate waiting
+    pub fn next_retry(&self) -> Option<Instant> {
+        let state = self.state.lock().expect("manager lock poisoned");
+        if state.active.len() >= self.max_peers {
+            return None;
+        }
+        let retry_at = state
+            .candidates
+            .iter()
+            .filter(|(peer, _)| !state.active.contains_key(*peer))
+            .map(|(_, candidate)| candidate.retry_at)
+            .min()?;
+        // a due candidate is only held back by slots another torrent has
+        Some(retry_at.max(Instant::now() + SLOT_POLL.min(self.backoff)))
+    }
+
+    /// Whether any peer may still be connected to, now or after a backoff
+    pub fn has_candidates(&self) -> bool {
+        let state = self.state.lock().expect("manager lock poisoned");
+        state.candidates.keys().any(|peer| !state.active.contains_key(peer))
+    }
+
+    fn failed(&self, state: &mut ManagerState, peer: SocketAddr) {
+        state.active.remove(&peer);
+        let Some(candidate) = state.candidates.get_mut(&peer) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            state.candidates.remove(&peer);
+            state.forgotten.insert(peer);
+            return;
+        }
+        candidate.retry_at = Instant::now() + self.backoff * 2u32.pow(candidate.failures - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn test_limits_backoff_and_forgetting() {
+        // a peer answering every handshake with its own, except for info hash [1; 20], and an
+        // address nobody listens on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut handshake = [0u8; 68];
+                    socket.read_exact(&mut handshake).await.unwrap();
+                    if handshake[28..48] == [1; 20] {
+                        handshake[28..48].copy_from_slice(&[2; 20]);
+                    }
+                    socket.write_all(&handshake).await.unwrap();
+                    let _ = socket.read(&mut handshake).await;
+                });
+            }
+        });
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let incoming: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let backoff = Duration::from_millis(20);
+        let manager = ConnectionManager::new(ConnectionSlots::new(2, 1), 10).with_backoff(backoff);
+        manager.add([dead, live], PeerSource::Tracker);
+        manager.add([dead], PeerSource::Pex);
+        assert_eq!(manager.source(&dead), Some(PeerSource::Tracker));
+
+        // a single half-open slot, the second attempt waits for the first one
+        assert_eq!(manager.next_attempt(), Some(dead));
+        assert_eq!(manager.next_attempt(), None);
+        assert!(manager.connect(dead, [0; 20], [0; 8], 1).await.is_err());
+        assert_eq!(manager.next_attempt(), Some(live));
+        // the stand-in echoes our handshake, extension bit included
+        let conn = manager.connect(live, [0; 20], constant::get_reserved(), 1).await.expect("Connect failed");
+        assert!(conn.extensions);
+        // the last connection slot goes to a peer that found us, the failed one waits for it
+        assert!(manager.accept(incoming));
+        tokio::time::sleep(2 * backoff).await;
+        assert_eq!(manager.next_attempt(), None);
+        manager.closed(incoming, true);
+
+        // every further failure doubles the wait, until the peer is forgotten
+        for failures in 2..=MAX_FAILURES {
+            assert_eq!(manager.next_attempt(), Some(dead));
+            assert!(manager.connect(dead, [0; 20], [0; 8], 1).await.is_err());
+            if failures < MAX_FAILURES {
+                let retry_at = manager.state.lock().unwrap().candidates[&dead].retry_at;
+                let wait = retry_at.saturating_duration_since(Instant::now());
+                assert!(wait > backoff * 2u32.pow(failures - 1) / 2, "{failures} failures: {wait:?}");
+                assert_eq!(manager.next_attempt(), None);
+                tokio::time::sleep_until(retry_at).await;
+            }
+        }
+        assert!(!manager.has_candidates());
+        manager.add([dead], PeerSource::Pex);
+        assert_eq!(manager.source(&dead), None, "Forgotten peer came back");
+        drop(conn);
+
+        // a peer that answers for another torrent is not connected
+        assert!(manager.connect(live, [1; 20], [0; 8], 1).await.is_err());
+    }
+}
@@ -1,3 +1,23 @@
 pub const fn get_extension_id() -> u8 {
-    return 6
-}
\ No newline at end of file
+    6
+}
+
+/// The id we advertise for ut_pex in our extension handshake
+pub const fn get_pex_extension_id() -> u8 {
+    7
+}
+
+/// Reserved bytes we download with, only the extension protocol bit (BEP 10) is set
+pub const fn get_reserved() -> [u8; 8] {
+    [0, 0, 0, 0, 0, 16, 0, 0]
+}
+
+/// The peer id we handshake with and announce to trackers
+pub const fn get_peer_id() -> [u8; 20] {
+    *b"ABCDEFGHIJKLMNOPQRST"
+}
+
+/// `get_peer_id` as tracker requests carry it
+pub fn get_peer_id_string() -> String {
+    String::from_utf8_lossy(&get_peer_id()).into_owned()
+}
@@ -0,0 +1,133 @@
+//! Blocks shared between peers downloading the same piece.
+//!
+//! Once every remaining piece is being downloaded, idle peers join in on those pieces
+//! (endgame mode). Each block that arrives is published here so the other peers can
+//! send `Cancel` for it instead of waiting on a slow peer to deliver it again.
+
+use crate::resume::Resume;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{futures::Notified, Notify};
+
+#[derive(Debug, Default)]
+pub struct SharedBlocks {
+    /// piece index -> blocks, only for pieces still being downloaded
+    pieces: Mutex<HashMap<usize, PieceBlocks>>,
+    changed: Notify,
+    /// blocks are also written to disk so an interrupted download keeps them
+    resume: Option<Arc<Resume>>,
+}
+
+#[derive(Debug, Default)]
+struct PieceBlocks {
+    /// block offset -> block
+    blocks: HashMap<u32, Vec<u8>>,
+    /// peers that delivered any of the blocks, they are to blame when the piece fails to verify
+    sources: HashSet<SocketAddr>,
+}
+
+impl SharedBlocks {
+    pub fn with_resume(resume: Arc<Resume>) -> Self {
+        Self {
+            resume: Some(resume),
+            ..Default::default()
+        }
+    }
+
+    /// Starts collecting blocks for a piece, blocks kept from an earlier attempt
+    /// or stored on disk by an earlier run are handed out right away
+    pub fn start(&self, piece: usize) {
+        let mut pieces = self.pieces.lock().expect("blocks lock poisoned");
+        if let Entry::Vacant(entry) = pieces.entry(piece) {
+            let stored = self.resume.as_ref().map(|resume| resume.stored_blocks(piece)).unwrap_or_default();
+            entry.insert(PieceBlocks {
+                blocks: stored.into_iter().collect(),
+                sources: HashSet::new(),
+            });
+        }
+    }
+
+    /// Publishes a block that arrived from `from`, then waits for it to be written to disk
+    pub async fn insert(&self, piece: usize, begin: u32, block: &[u8], from: Option<SocketAddr>) {
+        {
+            let mut pieces = self.pieces.lock().expect("blocks lock poisoned");
+            let Some(blocks) = pieces.get_mut(&piece) else { return };
+            let Entry::Vacant(entry) = blocks.blocks.entry(begin) else { return };
+            entry.insert(block.to_vec());
+            blocks.sources.extend(from);
+        }
+        self.changed.notify_waiters();
+        if let Some(resume) = &self.resume {
+            // losing a block here only costs downloading it again after a restart,
+            // the verified piece is written out in full if a block is missing
+            let _ = resume.block_received(piece, begin, block.to_vec()).await;
+        }
+    }
+
+    /// Copies a block some peer already downloaded into `into`, false if nobody has it yet
+    pub fn copy_block(&self, piece: usize, begin: u32, into: &mut [u8]) -> bool {
+        let pieces = self.pieces.lock().expect("blocks lock poisoned");
+        match pieces.get(&piece).and_then(|blocks| blocks.blocks.get(&begin)) {
+            Some(block) if block.len() == into.len() => {
+                into.copy_from_slice(block);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Peers whose blocks went into the piece so far
+    pub fn sources(&self, piece: usize) -> Vec<SocketAddr> {
+        let pieces = self.pieces.lock().expect("blocks lock poisoned");
+        pieces.get(&piece).map(|blocks| blocks.sources.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// False once the piece was finished, by whichever peer
+    pub fn is_active(&self, piece: usize) -> bool {
+        self.pieces.lock().expect("blocks lock poisoned").contains_key(&piece)
+    }
+
+    /// Drops the blocks of a piece that was verified
+    pub fn finish(&self, piece: usize) {
+        self.pieces.lock().expect("blocks lock poisoned").remove(&piece);
+        self.changed.notify_waiters();
+    }
+
+    /// Drops the blocks of a piece nobody is downloading anymore, they may be corrupt
+    pub fn discard(&self, piece: usize) {
+        self.finish(piece);
+        if let Some(resume) = &self.resume {
+            resume.discard(piece);
+        }
+    }
+
+    /// Resolves when a block is published or a piece finishes
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_blocks_shared_until_finished() {
+        let shared = SharedBlocks::default();
+        let mut block = [0u8; 3];
+        let peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        shared.insert(1, 0, &[1, 2, 3], Some(peer)).await;
+        assert!(!shared.is_active(1) && !shared.copy_block(1, 0, &mut block));
+        shared.start(1);
+        assert!(!shared.copy_block(1, 0, &mut block));
+        shared.insert(1, 0, &[1, 2, 3], Some(peer)).await;
+        assert!(shared.copy_block(1, 0, &mut block));
+        assert_eq!(block, [1, 2, 3]);
+        assert_eq!(shared.sources(1), vec![peer]);
+        shared.finish(1);
+        assert!(!shared.is_active(1));
+    }
+}
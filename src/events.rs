@@ -0,0 +1,187 @@
+//! What a download reports while it runs.
+//!
+//! The library never prints progress itself. Anyone interested, e.g. the CLI's
+//! progress line, subscribes to the `Events` of a download and receives every
+//! `DownloadEvent` from then on. `Progress` folds those events into the figures
+//! worth showing: how much is done, the rate and the time left.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+/// Events a subscriber may fall behind by before it misses some
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadEvent {
+    /// The download begins, pieces verified by an earlier run are not in `left`
+    Started { pieces: usize, length: usize, left: usize },
+    /// A tracker answered an announce
+    TrackerAnnounced { peers: usize },
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    PieceVerified { piece: usize, bytes: usize },
+    /// A piece did not match its hash and will be downloaded again
+    HashFailed { piece: usize },
+    /// Every wanted piece is verified
+    Completed,
+    /// Seeding begins with `have` of the torrent's `pieces` on disk
+    Seeding { have: usize, pieces: usize },
+    /// No port could be listened on, only peers we connect to take part
+    NotListening(String),
+    /// Accepting incoming peers fails, the listener waits longer before every new attempt.
+    /// Sent once until accepting works again.
+    AcceptFailed(String),
+}
+
+/// Sending side of a download's events, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<DownloadEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Receives every event sent from now on, a subscriber that falls more than
+    /// a thousand events behind gets `RecvError::Lagged` and skips ahead
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.0.subscribe()
+    }
+
+    /// Sends to every subscriber, without subscribers the event is dropped
+    pub fn send(&self, event: DownloadEvent) {
+        let _ = self.0.send(event);
+    }
+}
+
+/// Running totals of a download, built from its events
+#[derive(Debug)]
+pub struct Progress {
+    length: usize,
+    left: usize,
+    /// bytes verified since `started`, the rate ignores earlier runs
+    downloaded: usize,
+    started: Instant,
+    peers: usize,
+    hash_failures: usize,
+    pub completed: bool,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            length: 0,
+            left: 0,
+            downloaded: 0,
+            started: Instant::now(),
+            peers: 0,
+            hash_failures: 0,
+            completed: false,
+        }
+    }
+}
+
+impl Progress {
+    pub fn update(&mut self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::Started { length, left, .. } => {
+                *self = Self {
+                    length: *length,
+                    left: *left,
+                    ..Self::default()
+                };
+            }
+            DownloadEvent::TrackerAnnounced { .. }
+            | DownloadEvent::Seeding { .. }
+            | DownloadEvent::NotListening(_)
+            | DownloadEvent::AcceptFailed(_) => {}
+            DownloadEvent::PeerConnected(_) => self.peers += 1,
+            DownloadEvent::PeerDisconnected(_) => self.peers = self.peers.saturating_sub(1),
+            DownloadEvent::PieceVerified { bytes, .. } => {
+                self.downloaded += bytes;
+                self.left = self.left.saturating_sub(*bytes);
+            }
+            DownloadEvent::HashFailed { .. } => self.hash_failures += 1,
+            DownloadEvent::Completed => {
+                self.left = 0;
+                self.completed = true;
+            }
+        }
+    }
+
+    /// Bytes per second since the download started
+    pub fn rate(&self) -> f64 {
+        self.downloaded as f64 / self.started.elapsed().as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Time left at the current rate, unknown until something was downloaded
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        (self.downloaded > 0 && rate > 0.0).then(|| Duration::from_secs_f64(self.left as f64 / rate))
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = match self.length {
+            0 => 100.0,
+            length => (length - self.left.min(length)) as f64 * 100.0 / length as f64,
+        };
+        write!(f, "{percent:5.1}% of {}, {}/s", bytes(self.length as f64), bytes(self.rate()))?;
+        match self.eta() {
+            Some(eta) if !self.completed => {
+                let secs = eta.as_secs();
+                write!(f, ", ETA {}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)?;
+            }
+            _ => {}
+        }
+        write!(f, ", {} peers", self.peers)?;
+        if self.hash_failures > 0 {
+            write!(f, ", {} failed hash checks", self.hash_failures)?;
+        }
+        Ok(())
+    }
+}
+
+fn bytes(amount: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut amount = amount;
+    let mut unit = 0;
+    while amount >= 1024.0 && unit < UNITS.len() - 1 {
+        amount /= 1024.0;
+        unit += 1;
+    }
+    format!("{amount:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_follows_events() {
+        let mut progress = Progress::default();
+        assert_eq!(progress.eta(), None);
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        // half the torrent was verified by an earlier run
+        progress.update(&DownloadEvent::Started { pieces: 4, length: 4096, left: 2048 });
+        progress.update(&DownloadEvent::PeerConnected(peer));
+        progress.update(&DownloadEvent::PieceVerified { piece: 2, bytes: 1024 });
+        progress.update(&DownloadEvent::HashFailed { piece: 3 });
+        assert!(progress.eta().is_some());
+        let line = progress.to_string();
+        assert!(line.starts_with(" 75.0% of 4.0 KiB"), "{line}");
+        assert!(line.contains("ETA") && line.ends_with("1 peers, 1 failed hash checks"), "{line}");
+
+        progress.update(&DownloadEvent::PeerDisconnected(peer));
+        progress.update(&DownloadEvent::Completed);
+        let line = progress.to_string();
+        assert!(line.starts_with("100.0%") && !line.contains("ETA"), "{line}");
+    }
+}
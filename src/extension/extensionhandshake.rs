@@ -1,4 +1,4 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -9,7 +9,7 @@ pub struct ExtensionHandshake {
     /// Local TCP listen port
     #[serde(default)]
     #[serde(skip_serializing_if = "is_zero")]
-    pub p: u8,
+    pub p: u16,
 
     #[serde(default)]
     #[serde(skip_serializing_if = "is_zero")]
@@ -28,21 +28,49 @@ pub struct ExtensionHandshake {
     /// If this peer has an IPv6 interface, this is the compact representation of that address (16 bytes)
     #[serde(default = "ipv6_default")]
     #[serde(skip_serializing_if = "is_ipv6_default")]
+    #[serde(with = "compactip::v6")]
     pub ipv6: Ipv6Addr,
 
     /// If extend_from_slices peer has an IPv4 interface, this is the compact representation of that address (4 bytes).
     #[serde(default = "ipv4_default")]
     #[serde(skip_serializing_if = "is_ipv4_default")]
+    #[serde(with = "compactip::v4")]
     pub ipv4: Ipv4Addr,
 
     /// An integer, the number of outstanding request messages this client supports without dropping any. The default in in libtorrent is 250.
     #[serde(default)]
     #[serde(skip_serializing_if = "is_zero")]
-    pub reqq: u8,
+    pub reqq: u32,
 }
 
-fn is_zero(x: &u8) -> bool {
-    *x == 0
+fn is_zero<T: Default + PartialEq>(x: &T) -> bool {
+    *x == T::default()
+}
+
+impl ExtensionHandshake {
+    /// A handshake announcing the extensions in `m` and nothing else about us
+    pub fn new(m: M) -> Self {
+        Self {
+            m,
+            p: 0,
+            metadata_size: 0,
+            v: String::new(),
+            yourip: default_peer(),
+            ipv6: ipv6_default(),
+            ipv4: ipv4_default(),
+            reqq: 0,
+        }
+    }
+
+    /// Where the peer accepts connections over IPv6, if it told us
+    pub fn ipv6_addr(&self) -> Option<SocketAddr> {
+        (!is_ipv6_default(&self.ipv6) && self.p != 0).then(|| SocketAddr::new(self.ipv6.into(), self.p))
+    }
+
+    /// Where the peer accepts connections over IPv4, if it told us
+    pub fn ipv4_addr(&self) -> Option<SocketAddr> {
+        (!is_ipv4_default(&self.ipv4) && self.p != 0).then(|| SocketAddr::new(self.ipv4.into(), self.p))
+    }
 }
 
 fn is_ipv4_default(ipv4: &Ipv4Addr) -> bool{
@@ -86,6 +114,39 @@ pub fn default_peer() -> PeerIP {
     PeerIP::Ipv4(ipv4_default())
 }
 
+/// `ipv4` and `ipv6` are sent as compact byte strings, not as text
+mod compactip{
+    pub mod v4 {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::net::Ipv4Addr;
+
+        pub fn serialize<S: Serializer>(ip: &Ipv4Addr, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&ip.octets())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Ipv4Addr, D::Error> {
+            let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+            let octets: [u8; 4] = bytes.as_slice().try_into().map_err(serde::de::Error::custom)?;
+            Ok(Ipv4Addr::from(octets))
+        }
+    }
+
+    pub mod v6 {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::net::Ipv6Addr;
+
+        pub fn serialize<S: Serializer>(ip: &Ipv6Addr, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&ip.octets())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Ipv6Addr, D::Error> {
+            let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+            let octets: [u8; 16] = bytes.as_slice().try_into().map_err(serde::de::Error::custom)?;
+            Ok(Ipv6Addr::from(octets))
+        }
+    }
+}
+
 mod peerip{
     use std::net::{Ipv4Addr, Ipv6Addr};
 
@@ -105,8 +166,8 @@ mod peerip{
         fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
             where
                 E: serde::de::Error, {
-                    if ! (v.len() == 4 as usize || v.len() == 16 as usize) {
-                        return Err(E::custom(format!("Expecting length of 4 or 6")))
+                    if ! (v.len() == 4 || v.len() == 16) {
+                        return Err(E::custom("Expecting length of 4 or 6".to_string()))
                     }
 
                     let peer = 
@@ -191,4 +252,15 @@ mod tests {
 
         assert_eq!(utf_8, utf_8_2, "Should be equal");
     }
+
+    #[test]
+    fn test_ipv6_from_extension_handshake() {
+        let mut content = b"d4:ipv616:".to_vec();
+        content.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+        content.extend_from_slice(b"1:md11:ut_metadatai3ee1:pi51413ee");
+        let extension_handshake: ExtensionHandshake = serde_bencode::from_bytes(&content).expect("Convert to a struct");
+        assert_eq!(extension_handshake.ipv6_addr(), Some("[2001:db8::7]:51413".parse().unwrap()));
+        assert_eq!(extension_handshake.ipv4_addr(), None);
+        assert_eq!(serde_bencode::to_bytes(&extension_handshake).unwrap(), content);
+    }
 }
\ No newline at end of file
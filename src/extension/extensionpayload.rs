@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use crate::extension::{
     extensionhandshake::ExtensionHandshake,
-    extensionmetadata::ExtensionMetadata
+    extensionmetadata::ExtensionMetadata,
+    extensionpex::PexMessage
 };
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -16,7 +17,9 @@ pub struct ExtensionPayload{
 #[serde(untagged)]
 pub enum ExtensionType {
     ExtensionHandshakeMessage(ExtensionHandshake),
-    MetaDataMessage(ExtensionMetadata)
+    MetaDataMessage(ExtensionMetadata),
+    /// every field is optional so this has to stay last
+    PexMessage(PexMessage)
 }
 
 impl ExtensionPayload{
@@ -25,7 +28,7 @@ impl ExtensionPayload{
         let mut a: Vec<u8> = Vec::new();
         a.append(&mut self.extension_id.to_be_bytes().to_vec());
         a.append(&mut payload_vec);
-        return a
+        a
     }
 }
 
@@ -0,0 +1,57 @@
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::httprequest::{compact_v4, compact_v6};
+
+/// ut_pex message, peers are in compact form (6 bytes for IPv4, 18 bytes for IPv6)
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PexMessage {
+    #[serde(default, with = "serde_bytes")]
+    pub added: Vec<u8>,
+    /// one flag byte per added IPv4 peer (encryption, seed, ...)
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    pub added_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub added6: Vec<u8>,
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    pub added6_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    /// Peers that joined the swarm since the last message, IPv4 first
+    pub fn added_peers(&self) -> Vec<SocketAddr> {
+        let mut peers = compact_v4(&self.added);
+        peers.extend(compact_v6(&self.added6));
+        peers
+    }
+
+    pub fn dropped_peers(&self) -> Vec<SocketAddr> {
+        let mut peers = compact_v4(&self.dropped);
+        peers.extend(compact_v6(&self.dropped6));
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extension::extensionpex::PexMessage;
+
+    #[test]
+    fn test_pex_ipv4_and_ipv6_peers() {
+        let mut content = b"d5:added6:".to_vec();
+        content.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        content.extend_from_slice(b"6:added618:");
+        content.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+        content.extend_from_slice(b"8:added6.f1:\x027:dropped0:e");
+        let pex: PexMessage = serde_bencode::from_bytes(&content).expect("Convert to a struct");
+        assert_eq!(
+            pex.added_peers(),
+            vec!["10.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap()]
+        );
+        assert!(pex.dropped_peers().is_empty());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod extensionhandshake;
 pub mod extensionmetadata;
 pub mod extensionpayload;
+pub mod extensionpex;
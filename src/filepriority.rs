@@ -0,0 +1,116 @@
+//! Choosing which files of a torrent to download.
+//!
+//! Every file is skipped, downloaded normally or downloaded first. Pieces only
+//! exist for the torrent as a whole, so a piece is wanted when any file it
+//! overlaps is, and it is as urgent as the most urgent of those files. Pieces
+//! that overlap a skipped file as well are stored partly in a side file, see
+//! `Storage::with_priorities`.
+
+use crate::torrent::{FileEntry, Info};
+use anyhow::Context;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    #[default]
+    Normal,
+    High,
+}
+
+/// One priority per file of a torrent, in the order of the torrent's files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePriorities(Vec<FilePriority>);
+
+impl FilePriorities {
+    /// Every file downloaded normally
+    pub fn new(num_of_files: usize) -> Self {
+        Self(vec![FilePriority::Normal; num_of_files])
+    }
+
+    /// Only the files a selector like `0,2-4,7:high` names are downloaded, file indices
+    /// start at 0 and a `:high` suffix gets files downloaded before the others
+    pub fn select(num_of_files: usize, selector: &str) -> anyhow::Result<Self> {
+        let mut priorities = Self(vec![FilePriority::Skip; num_of_files]);
+        for item in selector.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (files, priority) = match item.split_once(':') {
+                Some((files, "high")) => (files, FilePriority::High),
+                Some((files, "normal")) => (files, FilePriority::Normal),
+                Some((_, priority)) => anyhow::bail!("Unknown priority {priority}, expected high or normal"),
+                None => (item, FilePriority::Normal),
+            };
+            let (first, last) = files.split_once('-').unwrap_or((files, files));
+            let first: usize = first.parse().with_context(|| format!("File index in {item}"))?;
+            let last: usize = last.parse().with_context(|| format!("File index in {item}"))?;
+            anyhow::ensure!(
+                first <= last && last < num_of_files,
+                "No files {first} to {last}, the torrent has {num_of_files}"
+            );
+            (first..=last).for_each(|file| priorities.set(file, priority));
+        }
+        Ok(priorities)
+    }
+
+    pub fn set(&mut self, file: usize, priority: FilePriority) {
+        self.0[file] = priority;
+    }
+
+    pub fn get(&self, file: usize) -> FilePriority {
+        self.0[file]
+    }
+
+    pub fn is_skipped(&self, file: usize) -> bool {
+        self.0[file] == FilePriority::Skip
+    }
+
+    /// The priority of every piece, the highest of the files it overlaps
+    pub fn piece_priorities(&self, info: &Info, files: &[FileEntry]) -> Vec<FilePriority> {
+        (0..info.num_pieces())
+            .map(|piece| {
+                info.files_for_piece(files, piece)
+                    .iter()
+                    .map(|slice| self.0[slice.file_index])
+                    .max()
+                    .unwrap_or(FilePriority::Skip)
+            })
+            .collect()
+    }
+
+    /// Pieces overlapping at least one file that is not skipped
+    pub fn wanted_pieces(&self, info: &Info, files: &[FileEntry]) -> Vec<usize> {
+        self.pieces_with(info, files, |priority| priority != FilePriority::Skip)
+    }
+
+    /// Pieces overlapping a high priority file
+    pub fn high_pieces(&self, info: &Info, files: &[FileEntry]) -> Vec<usize> {
+        self.pieces_with(info, files, |priority| priority == FilePriority::High)
+    }
+
+    fn pieces_with(&self, info: &Info, files: &[FileEntry], keep: impl Fn(FilePriority) -> bool) -> Vec<usize> {
+        let priorities = self.piece_priorities(info, files);
+        (0..priorities.len()).filter(|piece| keep(priorities[*piece])).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::multi_file_info;
+
+    #[test]
+    fn test_selected_files_decide_pieces() {
+        // files of 5, 20 and 7 bytes in pieces of 8
+        let info = multi_file_info(["a", "b", "c"]);
+        let files = info.file_entries().unwrap();
+
+        let priorities = FilePriorities::select(3, "0, 2:high").unwrap();
+        assert!(priorities.is_skipped(1));
+        assert_eq!(priorities.wanted_pieces(&info, &files), vec![0, 3]);
+        assert_eq!(priorities.high_pieces(&info, &files), vec![3]);
+        assert_eq!(FilePriorities::new(3).wanted_pieces(&info, &files), vec![0, 1, 2, 3]);
+        assert_eq!(FilePriorities::select(3, "1-2").unwrap().wanted_pieces(&info, &files), vec![0, 1, 2, 3]);
+
+        assert!(FilePriorities::select(3, "3").is_err());
+        assert!(FilePriorities::select(3, "2-1").is_err());
+        assert!(FilePriorities::select(3, "0:low").is_err());
+    }
+}
@@ -12,7 +12,25 @@ pub struct Handshake{
     pub peer_id: [u8;20]
 }
 
+/// Reserved bit of the extension protocol (BEP 10), in byte 5
+const EXTENSION_BIT: u8 = 0x10;
+
+/// Whether handshake reserved bytes announce the extension protocol
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[5] & EXTENSION_BIT != 0
+}
+
 impl Handshake {
+    pub fn from_bytes(buf: &[u8; 68]) -> Self {
+        Self {
+            protocol_length: buf[0],
+            protocol_name: buf[1..20].try_into().expect("slice length not 19"),
+            reserved: buf[20..28].try_into().expect("slice length not 8"),
+            info_hash: buf[28..48].try_into().expect("slice length not 20"),
+            peer_id: buf[48..68].try_into().expect("slice length not 20"),
+        }
+    }
+
     pub fn as_bytes(&self) -> [u8; 68] {
         let mut buf = [0u8; 68];
         buf[0] = self.protocol_length;
@@ -1,76 +1,217 @@
 /// Tracker Request and Response
 use serde::{Serialize, Deserialize};
-use peers::Peers;
-#[derive(Debug, Serialize, Deserialize)]
+use thiserror::Error;
+pub use peers::{compact_v4, compact_v6, to_compact, PeerEntry, Peers, Peers6};
+use std::net::SocketAddr;
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Request{
     pub peer_id: String,
     pub port: u16,
     pub uploaded: usize,
     pub downloaded: usize,
     pub left: usize,
-    pub compact: u8
+    pub compact: u8,
+    /// left out for regular re-announces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    /// random value that lets the tracker recognise us across IP changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// echoed back from a previous response's `tracker id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response{
     pub interval: usize,
-    pub peers: Peers
+    /// re-announcing more often than this is not allowed
+    #[serde(rename = "min interval", default, skip_serializing_if = "Option::is_none")]
+    pub min_interval: Option<usize>,
+    #[serde(rename = "tracker id", default, skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+    /// the announce went through but the tracker has something to say
+    #[serde(rename = "warning message", default, skip_serializing_if = "Option::is_none")]
+    pub warning_message: Option<String>,
+    /// number of seeders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<usize>,
+    /// number of leechers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<usize>,
+    /// compact string or a list of dictionaries, depending on the tracker
+    #[serde(default)]
+    pub peers: Peers,
+    /// IPv6 peers in compact form
+    #[serde(default, skip_serializing_if = "Peers6::is_empty")]
+    pub peers6: Peers6,
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The tracker refused the request, the reason is meant for humans
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("invalid tracker response: {0}")]
+    Invalid(#[from] serde_bencode::Error),
+}
+
+/// Only used to look for `failure reason` before decoding a full Response
+#[derive(Deserialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+impl Peers6 {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Response {
+    /// IPv4 and IPv6 peers together
+    pub fn peer_addrs(&self) -> Vec<SocketAddr> {
+        self.peers.0.iter().chain(&self.peers6.0).copied().collect()
+    }
+
+    /// Decodes a tracker reply, a `failure reason` comes back as TrackerError::Failure
+    pub fn from_bytes(bytes: &[u8]) -> Result<Response, TrackerError> {
+        if let Ok(FailureResponse { failure_reason: Some(reason) }) = serde_bencode::from_bytes(bytes) {
+            return Err(TrackerError::Failure(reason));
+        }
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
 }
 
 mod peers{
-    use serde::de::{ Deserialize};
+    use serde::de::{ Deserialize, SeqAccess};
     use serde::ser::{Serialize, Serializer};
-    use std::net::{Ipv4Addr, SocketAddrV4};
-    #[derive(Debug)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    #[derive(Debug, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
     struct IPeers;
 
+    /// BEP 7 `peers6`, 18 bytes per address
+    #[derive(Debug, Default)]
+    pub struct Peers6(pub Vec<SocketAddr>);
+    struct IPeers6;
+
+    /// A peer in the non-compact (dictionary list) form
+    #[derive(Debug, serde::Deserialize)]
+    pub struct PeerEntry {
+        /// dotted quad, hexed IPv6 or a DNS name
+        pub ip: String,
+        pub port: u16,
+        #[serde(rename = "peer id", default)]
+        pub peer_id: Option<serde_bytes::ByteBuf>,
+    }
+
+    /// 4 address bytes followed by a big endian port
+    pub fn compact_v4(v: &[u8]) -> Vec<SocketAddr> {
+        v.chunks_exact(6).map(|chunk|{
+            SocketAddr::new(
+                Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]).into(),
+                u16::from_be_bytes([chunk[4], chunk[5]]),
+            )
+        }).collect()
+    }
+
+    /// 16 address bytes followed by a big endian port
+    pub fn compact_v6(v: &[u8]) -> Vec<SocketAddr> {
+        v.chunks_exact(18).map(|chunk|{
+            let octets: [u8; 16] = chunk[..16].try_into().expect("slice length not 16");
+            SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                u16::from_be_bytes([chunk[16], chunk[17]]),
+            )
+        }).collect()
+    }
+
+    /// Compact form of the addresses of one family, the others are skipped
+    pub fn to_compact(peers: &[SocketAddr], v6: bool) -> Vec<u8> {
+        let mut single_slice = Vec::new();
+        for socket_address in peers {
+            match socket_address.ip() {
+                IpAddr::V4(ip) if !v6 => single_slice.extend(ip.octets()),
+                IpAddr::V6(ip) if v6 => single_slice.extend(ip.octets()),
+                _ => continue,
+            }
+            single_slice.extend(socket_address.port().to_be_bytes());
+        }
+        single_slice
+    }
+
     impl<'de> serde::de::Visitor<'de> for IPeers {
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-            // assert!(formatter % 20 == 0, "Length of pieces data mismatch");
-            write!(formatter, "a byte representation of peer addresses, where each address is 6 bytes")
+            write!(formatter, "a byte representation of peer addresses, where each address is 6 bytes, or a list of peer dictionaries")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>, {
+                    let mut peers = Vec::new();
+                    while let Some(entry) = seq.next_element::<PeerEntry>()? {
+                        // DNS names are left out, we only connect to addresses
+                        if let Ok(ip) = entry.ip.parse::<IpAddr>() {
+                            peers.push(SocketAddr::new(ip, entry.port));
+                        }
+                    }
+                    Ok(Peers(peers))
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
             where
                 E: serde::de::Error, {
-                    if &v.len() % 6 != 0{
-                        return Err(E::custom(format!("Not a multiple of 6")))
+                    if !v.len().is_multiple_of(6){
+                        return Err(E::custom("Not a multiple of 6".to_string()))
                     }
+                    Ok(Peers(compact_v4(v)))
+        }
+    }
 
-                    // let mut vector: Vec<SocketAddrV4> = Vec::new();
-
-                    // v.chunks_exact(6).for_each(|chunk| {
-                    //     let a: [u8;4] = chunk[0..4].try_into().unwrap();
-                    //     let a: Ipv4Addr = a.try_into().unwrap();
-                    //     let b: u16 = u16::from_be_bytes(chunk[4..].try_into().unwrap());
-                    //     let socket_address = SocketAddrV4::new(a,b);
-                    //     vector.push(socket_address);
-                    // });
-                            
-                    // Ok(Peers(vector))
-
-                    Ok(Peers(
-                        v.chunks_exact(6).map(|chunk|{
-                            SocketAddrV4::new(
-                                Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
-                                u16::from_be_bytes([chunk[4], chunk[5]]),
-                            )
-                        }).collect()
-                    ))
+    impl<'de> serde::de::Visitor<'de> for IPeers6 {
+        type Value = Peers6;
 
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "a byte representation of peer addresses, where each address is 18 bytes")
         }
-    }
 
+        fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error, {
+                    if !v.len().is_multiple_of(18){
+                        return Err(E::custom("Not a multiple of 18".to_string()))
+                    }
+                    Ok(Peers6(compact_v6(v)))
+        }
+    }
 
     impl<'de> Deserialize<'de> for Peers {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: serde::Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(IPeers)
+            deserializer.deserialize_any(IPeers)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Peers6 {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(IPeers6)
         }
     }
 
@@ -79,15 +220,67 @@ mod peers{
         where
             S: Serializer,
         {
-            let mut single_slice = Vec::with_capacity(6 * self.0.len());
-            for socket_address in &self.0 {
-                single_slice.extend(socket_address.ip().octets());
-                single_slice.extend(socket_address.port().to_be_bytes());
-            }
-            serializer.serialize_bytes(&single_slice)
+            serializer.serialize_bytes(&to_compact(&self.0, false))
+        }
+    }
+
+    impl Serialize for Peers6 {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_bytes(&to_compact(&self.0, true))
         }
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_reason_is_typed() {
+        let error = Response::from_bytes(b"d14:failure reason17:torrent not founde").unwrap_err();
+        assert!(matches!(error, TrackerError::Failure(reason) if reason == "torrent not found"));
+        assert!(matches!(Response::from_bytes(b"d5:peers0:e"), Err(TrackerError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_full_compact_response() {
+        let response = Response::from_bytes(
+            b"d8:completei3e10:incompletei7e8:intervali1800e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id2:id15:warning message4:slowe",
+        )
+        .expect("Valid response");
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.tracker_id.as_deref(), Some("id"));
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert_eq!((response.complete, response.incomplete), (Some(3), Some(7)));
+        assert_eq!(response.peers.0, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_dictionary_peers() {
+        let mut body = b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:".to_vec();
+        body.extend_from_slice(&[0xAA; 20]);
+        body.extend_from_slice(b"4:porti6881eed2:ip11:example.org4:porti1eeee");
+        let response = Response::from_bytes(&body).expect("Valid response");
+        assert_eq!(response.peers.0, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_ipv6_peers() {
+        let mut body = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+        body.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+        body.push(b'e');
+        let response = Response::from_bytes(&body).expect("Valid response");
+        assert_eq!(
+            response.peer_addrs(),
+            vec!["127.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap()]
+        );
+        assert_eq!(serde_bencode::to_bytes(&response).unwrap(), body);
 
+        let dict = Response::from_bytes(b"d8:intervali900e5:peersld2:ip11:2001:db8::24:porti80eeee").unwrap();
+        assert_eq!(dict.peers.0, vec!["[2001:db8::2]:80".parse().unwrap()]);
+    }
+}
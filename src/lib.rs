@@ -1,8 +1,27 @@
+pub mod bencode;
 pub mod handshake;
 pub mod magnet;
 pub mod message;
 pub mod httprequest;
 pub mod torrent;
+pub mod tracker;
+pub mod udptracker;
+pub mod requestwindow;
+pub mod bitfield;
+pub mod peerconnection;
+pub mod listener;
+pub mod connectionmanager;
+pub mod swarm;
+pub mod piecepicker;
+pub mod endgame;
+pub mod resume;
+pub mod storage;
+pub mod filepriority;
+pub mod seed;
+pub mod torrentreader;
+pub mod choker;
+pub mod ratelimit;
+pub mod events;
 pub mod utils;
 pub mod extension;
 pub mod constant;
\ No newline at end of file
@@ -0,0 +1,257 @@
+//! Accepting connections from peers.
+//!
+//! Every torrent the session runs registers its info hash. An incoming peer
+//! handshakes first; if its info hash belongs to a registered torrent we answer
+//! the handshake and hand the connection to that torrent, otherwise the
+//! connection is dropped without an answer.
+
+use crate::{
+    events::{DownloadEvent, Events},
+    handshake::{supports_extensions, Handshake},
+    message::MessageFramer,
+    peerconnection::PeerConnection,
+};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_util::codec::Framed;
+
+/// Ports tried in order, the first free one is announced to trackers
+pub const LISTEN_PORTS: std::ops::RangeInclusive<u16> = 6881..=6889;
+/// An incoming peer has this long to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait after a failed accept, e.g. when we ran out of file descriptors. It doubles
+/// with every further failure in a row, up to `MAX_ACCEPT_BACKOFF`.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Where connections for one torrent go
+struct Route {
+    connections: mpsc::UnboundedSender<PeerConnection>,
+    num_of_pieces: usize,
+    reserved: [u8; 8],
+}
+
+/// The torrents accepting incoming peers, by info hash
+#[derive(Clone, Default)]
+pub struct Routes(Arc<Mutex<HashMap<[u8; 20], Route>>>);
+
+impl Routes {
+    /// Incoming peers asking for `info_hash` arrive on the returned channel, already handshaked
+    pub fn register(&self, info_hash: [u8; 20], num_of_pieces: usize, reserved: [u8; 8]) -> mpsc::UnboundedReceiver<PeerConnection> {
+        let (connections, receiver) = mpsc::unbounded_channel();
+        let route = Route {
+            connections,
+            num_of_pieces,
+            reserved,
+        };
+        self.0.lock().expect("routes lock poisoned").insert(info_hash, route);
+        receiver
+    }
+
+    /// Peers asking for `info_hash` are turned away from now on
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.0.lock().expect("routes lock poisoned").remove(info_hash);
+    }
+
+    /// Answers the handshake and routes the connection, unknown info hashes are rejected
+    async fn accept(&self, mut socket: TcpStream, peer_id: [u8; 20]) -> anyhow::Result<()> {
+        let mut handshake = [0u8; 68];
+        timeout(HANDSHAKE_TIMEOUT, socket.read_exact(&mut handshake))
+            .await
+            .context("Handshake timed out")?
+            .context("Read handshake")?;
+        anyhow::ensure!(
+            handshake[0] == 19 && &handshake[1..20] == b"BitTorrent protocol",
+            "Not a BitTorrent handshake"
+        );
+        let info_hash: [u8; 20] = handshake[28..48].try_into().expect("slice length not 20");
+        let (num_of_pieces, reserved) = {
+            let routes = self.0.lock().expect("routes lock poisoned");
+            let route = routes
+                .get(&info_hash)
+                .with_context(|| format!("Unknown info hash {}", hex::encode(info_hash)))?;
+            (route.num_of_pieces, route.reserved)
+        };
+        let reply = Handshake {
+            protocol_length: 19,
+            protocol_name: *b"BitTorrent protocol",
+            reserved,
+            info_hash,
+            peer_id,
+        };
+        socket.write_all(&reply.as_bytes()).await.context("Send handshake")?;
+        let mut conn = PeerConnection::new(Framed::new(socket, MessageFramer), num_of_pieces);
+        conn.extensions = supports_extensions(&reserved) && supports_extensions(&Handshake::from_bytes(&handshake).reserved);
+        // the torrent may have stopped while we were answering
+        let routes = self.0.lock().expect("routes lock poisoned");
+        let route = routes.get(&info_hash).context("Torrent stopped")?;
+        route.connections.send(conn).ok().context("Torrent stopped")
+    }
+}
+
+/// Listens for peers on behalf of every registered torrent
+pub struct Listener {
+    /// all on the same port
+    tcp_listeners: Vec<TcpListener>,
+    routes: Routes,
+    peer_id: [u8; 20],
+    events: Events,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr, peer_id: [u8; 20]) -> anyhow::Result<Self> {
+        let tcp_listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Listen on {addr}"))?;
+        Ok(Self {
+            tcp_listeners: vec![tcp_listener],
+            routes: Routes::default(),
+            peer_id,
+            events: Events::default(),
+        })
+    }
+
+    /// Binds the first free port of `LISTEN_PORTS` on every IPv6 and IPv4 interface.
+    /// Where `[::]` takes IPv4 peers as well binding `0.0.0.0` fails, only one of them is needed.
+    pub async fn bind_default(peer_id: [u8; 20]) -> anyhow::Result<Self> {
+        for port in LISTEN_PORTS {
+            let v6 = TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).await;
+            let v4 = TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await;
+            let tcp_listeners: Vec<TcpListener> = [v6, v4].into_iter().flatten().collect();
+            if !tcp_listeners.is_empty() {
+                return Ok(Self {
+                    tcp_listeners,
+                    routes: Routes::default(),
+                    peer_id,
+                    events: Events::default(),
+                });
+            }
+        }
+        anyhow::bail!("No free port in {LISTEN_PORTS:?}")
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.tcp_listeners[0].local_addr().context("Listener address")
+    }
+
+    /// Failures to accept peers are reported to `events`
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Handle to register torrents, it stays valid while the listener runs
+    pub fn routes(&self) -> Routes {
+        self.routes.clone()
+    }
+
+    /// Accepts peers until the task is dropped, each handshake is answered in its own task
+    pub async fn run(self) {
+        let mut accepting = JoinSet::new();
+        for tcp_listener in self.tcp_listeners {
+            let (routes, peer_id, events) = (self.routes.clone(), self.peer_id, self.events.clone());
+            accepting.spawn(async move {
+                let mut backoff: Option<Duration> = None;
+                loop {
+                    let socket = match tcp_listener.accept().await {
+                        Ok((socket, _)) => socket,
+                        Err(e) => {
+                            // retrying at once would spin for as long as the error lasts
+                            let wait = match backoff {
+                                Some(wait) => (2 * wait).min(MAX_ACCEPT_BACKOFF),
+                                None => {
+                                    events.send(DownloadEvent::AcceptFailed(e.to_string()));
+                                    ACCEPT_BACKOFF
+                                }
+                            };
+                            backoff = Some(wait);
+                            tokio::time::sleep(wait).await;
+                            continue;
+                        }
+                    };
+                    backoff = None;
+                    let routes = routes.clone();
+                    tokio::spawn(async move {
+                        // a rejected peer only sees the connection close
+                        let _ = routes.accept(socket, peer_id).await;
+                    });
+                }
+            });
+        }
+        // dropping the task drops the set, which stops every listener
+        while accepting.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(addr: SocketAddr, info_hash: [u8; 20]) -> Option<[u8; 68]> {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let request = Handshake {
+            protocol_length: 19,
+            protocol_name: *b"BitTorrent protocol",
+            reserved: [0; 8],
+            info_hash,
+            peer_id: [1; 20],
+        };
+        socket.write_all(&request.as_bytes()).await.unwrap();
+        let mut reply = [0u8; 68];
+        socket.read_exact(&mut reply).await.ok().map(|_| reply)
+    }
+
+    #[tokio::test]
+    async fn test_routes_known_info_hash_only() {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), [9; 20]).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routes = listener.routes();
+        let mut incoming = routes.register([5; 20], 3, [0, 0, 0, 0, 0, 16, 0, 0]);
+        tokio::spawn(listener.run());
+
+        assert!(handshake(addr, [6; 20]).await.is_none(), "Unknown info hash was answered");
+
+        let reply = handshake(addr, [5; 20]).await.expect("Known info hash was rejected");
+        assert_eq!(&reply[28..48], &[5; 20]);
+        assert_eq!(&reply[48..], &[9; 20]);
+        assert_eq!(reply[25], 16);
+        let conn = incoming.recv().await.expect("Connection routed to the torrent");
+        assert_eq!(conn.have.len(), 3);
+        // the peer did not set the extension bit, so it gets no extension messages
+        assert!(!conn.extensions);
+
+        routes.unregister(&[5; 20]);
+        assert!(handshake(addr, [5; 20]).await.is_none(), "Stopped torrent was answered");
+    }
+
+    #[tokio::test]
+    async fn test_default_listener_takes_ipv4_and_ipv6() {
+        let listener = Listener::bind_default([9; 20]).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut incoming = listener.routes().register([5; 20], 3, [0; 8]);
+        tokio::spawn(listener.run());
+
+        let mut loopbacks = vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))];
+        // hosts without IPv6 only get the IPv4 listener
+        if std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).is_ok() {
+            loopbacks.push(SocketAddr::from((Ipv6Addr::LOCALHOST, port)));
+        }
+        for addr in loopbacks {
+            assert!(handshake(addr, [5; 20]).await.is_some(), "No answer on {addr}");
+            let conn = incoming.recv().await.expect("Connection routed to the torrent");
+            assert_eq!(conn.peer_addr().map(|peer| peer.ip()), Some(addr.ip()));
+        }
+    }
+}
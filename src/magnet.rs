@@ -9,21 +9,16 @@ pub struct Magnet{
 }
 
 impl Magnet{
-    pub fn new(magnet_link: &String) -> anyhow::Result<Magnet>{
+    pub fn new(magnet_link: &str) -> anyhow::Result<Magnet>{
         let (left_string,url_string) = magnet_link
             .split_once("&tr=")
-            .and_then(|(left,right)|{
-                Some((left, decode(right).expect("UTF-8")))
-            })
+            .map(|(left,right)| (left, decode(right).expect("UTF-8")))
             .context("Splitting at url")?;
 
         let (info_hash, name )= left_string
             .split_once("&dn=")
             .and_then(|(left,name)|{
-                left.split_once("btih:")
-                .and_then(|(_,url)|{
-                    Some((url, name))
-                })
+                left.split_once("btih:").map(|(_,url)| (url, name))
             }).context("Splitting for hash and name failed")?;     
 
         Ok(
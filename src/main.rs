@@ -1,22 +1,51 @@
 use codecrafters_bittorrent::{
+    bencode::BencodeValue,
+    choker::{Choker, OPTIMISTIC_SLOTS, UPLOAD_SLOTS},
+    constant,
+    events::{DownloadEvent, Events, Progress},
+    httprequest::TrackerError,
     magnet::Magnet, 
+    peerconnection::PeerConnection,
+    ratelimit::{Limits, RateLimit, Schedule},
+    requestwindow::RequestWindow,
     torrent::Torrent, 
     utils::{
-        self, decode_bencoded_value, establish_handshake, establish_handshake_and_download, get_peers_from_tracker_url, read_and_deserialize_torrent
+        self, establish_handshake, establish_handshake_and_download, get_peers_from_tracker_url, read_and_deserialize_torrent
     },
-    message::{Message, MessageTag, Payload},
 };
-use std::{net::SocketAddrV4};
+use std::{net::SocketAddr, ops::Range, sync::Arc};
 use anyhow::Context;
 use clap::{Parser, Subcommand};
-use hex;
-use futures_util::{sink::SinkExt, stream::StreamExt};
+use tokio::{sync::broadcast, task::JoinHandle};
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
+#[clap(rename_all = "snake_case")]
 struct Args {
     #[command(subcommand)]
     operation: Type,
+    /// Do not show download progress
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    /// Download limit in KiB/s
+    #[arg(long, global = true)]
+    download_rate: Option<u64>,
+    /// Upload limit in KiB/s
+    #[arg(long, global = true)]
+    upload_rate: Option<u64>,
+    /// Working hours, e.g. 9-17, Monday to Friday the working rates apply.
+    /// They are in UTC unless --utc_offset says otherwise
+    #[arg(long, global = true, value_parser = parse_hours)]
+    working_hours: Option<Range<u8>>,
+    /// Download limit in KiB/s during working hours, --download_rate when left out
+    #[arg(long, global = true, requires = "working_hours")]
+    working_download_rate: Option<u64>,
+    /// Upload limit in KiB/s during working hours, --upload_rate when left out
+    #[arg(long, global = true, requires = "working_hours")]
+    working_upload_rate: Option<u64>,
+    /// Local time minus UTC in minutes, for the working hours. The default 0 means UTC
+    #[arg(long, global = true, default_value_t = 0, allow_negative_numbers = true)]
+    utc_offset: i32,
 }
 
 #[derive(Debug, Subcommand)]
@@ -24,6 +53,9 @@ struct Args {
 enum Type {
     Decode {
         decode: String,
+        /// treat the argument as a path and decode the file's contents
+        #[arg(long)]
+        file: bool,
     },
     Info {
         info: String,
@@ -33,9 +65,14 @@ enum Type {
         // #[arg(default_value_t = Reserved::default())]
         // reserved: Reserved
     },
+    /// swarm statistics for one or many .torrent files or magnet links
+    Scrape {
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
     Handshake {
         info: String,
-        peer: SocketAddrV4,
+        peer: SocketAddr,
     },
     DownloadPiece {
         #[arg(short)]
@@ -47,6 +84,21 @@ enum Type {
         #[arg(short)]
         output: String,
         info: String,
+        /// files to download by index, e.g. 0,2-4,7:high, the others are skipped
+        #[arg(long)]
+        files: Option<String>,
+    },
+    /// upload the pieces of existing data to the torrent's peers
+    Seed {
+        info: String,
+        /// the file, or the root directory of a multi file torrent
+        data: String,
+        /// peers uploaded to at once, chosen by their rate
+        #[arg(long, default_value_t = UPLOAD_SLOTS)]
+        upload_slots: usize,
+        /// peers uploaded to at once, chosen at random
+        #[arg(long, default_value_t = OPTIMISTIC_SLOTS)]
+        optimistic_slots: usize,
     },
     MagnetParse {
         magnet: String,
@@ -67,6 +119,9 @@ enum Type {
         #[arg(short)]
         output: String,
         magnet: String,
+        /// files to download by index, e.g. 0,2-4,7:high, the others are skipped
+        #[arg(long)]
+        files: Option<String>,
     },
 }
 
@@ -85,10 +140,27 @@ enum Type {
 async fn main() -> anyhow::Result<()> {
     let arg = Args::parse();
     // println!("{:?}",arg);
+    if let Err(e) = run(&arg).await {
+        // a tracker refusing us is not a bug, print its reason instead of the whole error chain
+        if let Some(TrackerError::Failure(reason)) = e.chain().find_map(|c| c.downcast_ref::<TrackerError>()) {
+            eprintln!("Tracker failure: {reason}");
+            std::process::exit(1);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn run(arg: &Args) -> anyhow::Result<()> {
     match &arg.operation {
-        Type::Decode { decode } => {
-            let decoded_value = decode_bencoded_value(&decode).0;
-            println!("{decoded_value}");
+        Type::Decode { decode, file } => {
+            let encoded_value = if *file {
+                std::fs::read(decode).context("Read file")?
+            } else {
+                decode.as_bytes().to_vec()
+            };
+            let decoded_value = BencodeValue::decode(&encoded_value).context("Decoding bencoded value")?;
+            println!("{}", decoded_value.to_json());
         }
         Type::Info { info } => {
             let tor: Torrent =
@@ -96,7 +168,7 @@ async fn main() -> anyhow::Result<()> {
             let info_hash = tor.info_hash();
             let piece_length = &tor.info.pieces_length;
             println!("Tracker URL: {}", &tor.announce);
-            println!("Length: {}", &tor.info.length);
+            println!("Length: {}", tor.info.total_length());
             println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", &piece_length);
             println!("Piece Hashes:");
@@ -104,6 +176,12 @@ async fn main() -> anyhow::Result<()> {
             for piece in &tor.info.pieces.0 {
                 println!("{}", hex::encode(piece));
             }
+            if tor.info.is_multi_file() {
+                println!("Files:");
+                for file in tor.info.file_entries()? {
+                    println!("{} ({} bytes)", file.path.display(), file.length);
+                }
+            }
         }
         Type::Peers { info } => {
             // Type::Peers { info, reserved } => {
@@ -111,11 +189,35 @@ async fn main() -> anyhow::Result<()> {
             let (response, _) = get_peers_from_tracker_url(info)
                 .await
                 .context("Unable to get response")?;
-            for socket_address in &response.peers.0 {
-                let a = format!("{}:{}", socket_address.ip(), socket_address.port());
-                println!("{}", a);
+            if let Some(warning) = &response.warning_message {
+                eprintln!("Tracker warning: {warning}");
+            }
+            for socket_address in response.peer_addrs() {
+                println!("{}", socket_address);
             }
         }
+        Type::Scrape { targets } => {
+            let swarms = utils::scrape_swarms(targets)
+                .await
+                .context("Unable to scrape trackers")?;
+            let mut failed = 0;
+            for (info_hash, stats) in &swarms {
+                match stats {
+                    Ok(stats) => println!(
+                        "{}: complete {}, incomplete {}, downloaded {}",
+                        hex::encode(info_hash),
+                        stats.complete,
+                        stats.incomplete,
+                        stats.downloaded
+                    ),
+                    Err(e) => {
+                        failed += 1;
+                        eprintln!("{}: {e:#}", hex::encode(info_hash));
+                    }
+                }
+            }
+            anyhow::ensure!(failed == 0, "{failed} of {} swarms could not be scraped", swarms.len());
+        }
         Type::Handshake { info, peer } => {
             let tor: Torrent =
                 read_and_deserialize_torrent(info).context("Unable to read and deserialize")?;
@@ -131,35 +233,55 @@ async fn main() -> anyhow::Result<()> {
             info,
             index,
         } => {
-            let reserved: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-            let _res = establish_handshake_and_download(&output, &info, Some(*index), reserved)
+            let reserved = constant::get_reserved();
+            let events = Events::default();
+            let progress = show_progress(&events, arg.quiet);
+            let res = establish_handshake_and_download(output, info, Some(*index), reserved, events, limits(arg), None)
                 .await
                 .context("Downloading a single piece");
+            finish_progress(progress).await;
+            res?;
         }
-        Type::Download { output, info } => {
-            let reserved: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-            let _res = establish_handshake_and_download(&output, &info, None, reserved)
+        Type::Download { output, info, files } => {
+            let reserved = constant::get_reserved();
+            let events = Events::default();
+            let progress = show_progress(&events, arg.quiet);
+            let res = establish_handshake_and_download(output, info, None, reserved, events, limits(arg), files.as_deref())
                 .await
                 .context("Downloading all pieces");
+            finish_progress(progress).await;
+            res?;
+        }
+        Type::Seed { info, data, upload_slots, optimistic_slots } => {
+            let reserved = constant::get_reserved();
+            let choker = Choker::new(*upload_slots, *optimistic_slots);
+            let events = Events::default();
+            let notices = show_notices(&events);
+            let res = utils::seed_torrent(info, data, reserved, choker, limits(arg), events)
+                .await
+                .context("Seeding");
+            let _ = notices.await;
+            res?;
         }
         Type::MagnetParse { magnet } => {
-            let magnet: Magnet = Magnet::new(&magnet).context("Parsing failed")?;
+            let magnet: Magnet = Magnet::new(magnet).context("Parsing failed")?;
             println!("Tracker URL: {}", &magnet.url);
             println!("Info Hash: {}", &magnet.info_hash);
         }
         Type::MagnetHandshake { magnet } => {
-            let (extension_payload,_)  = utils::magnet_handshake(magnet)
+            let (extension_payload, _, peer_id) = utils::magnet_handshake(magnet)
                 .await
                 .context("Failed to receive magnet handshake")?;
+            println!("Peer ID: {}", peer_id);
             println!("Peer Metadata Extension ID: {:?}", extension_payload.m.ut_metadata);
         },
         Type::MagnetInfo { magnet } => {
-            let (torrent,_)  = utils::get_magnet_metadata(magnet)
+            let (torrent, _, _)  = utils::get_magnet_metadata(magnet)
                 .await
                 .context("Failed to receive magnet handshake")?;
             println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
-            println!("Info Hash: {}", hex::encode(&torrent.info_hash()));
+            println!("Length: {}", torrent.info.total_length());
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
             println!("Piece Length: {}", &torrent.info.pieces_length);
             println!("Piece Hashes:");
             for piece in &torrent.info.pieces.0{
@@ -167,24 +289,21 @@ async fn main() -> anyhow::Result<()> {
             }    
         },
         Type::MagnetDownloadPiece { output, magnet, index } => {
-            let (torrent,mut tcp_stream)  = utils::get_magnet_metadata(magnet)
+            let (torrent, tcp_stream, peer_handshake)  = utils::get_magnet_metadata(magnet)
                 .await
                 .context("Failed to receive magnet meta data")?;
+            anyhow::ensure!(*index < torrent.info.num_pieces(), "No piece {index}, the torrent has {}", torrent.info.num_pieces());
+            let mut conn = PeerConnection::new(tcp_stream, torrent.info.num_pieces());
+            conn.set_limits(limits(arg));
 
-            let message_to_send = Message {
-                message_tag: MessageTag::Interested,
-                payload: Payload::SimplePayload(Vec::new()),
-            };
-            let _message_sent = tcp_stream.send(message_to_send).await;
+            conn.set_interested(true).await.context("Send interested")?;
            
-            let message_received = tcp_stream
-                .next()
+            conn.wait_for_unchoke()
                 .await
-                .expect("Expecting a unchoke")
-                .context("Message was invalid")?;
-            assert_eq!(message_received.message_tag, MessageTag::Unchoke);
+                .context("Expecting a unchoke")?;
 
-            let res: Vec<u8> = utils::fetch_a_piece(&torrent, &mut tcp_stream, *index)
+            let mut window = RequestWindow::from_handshake(&peer_handshake);
+            let res: Vec<u8> = utils::fetch_a_piece(&torrent, &mut conn, *index, &mut window)
                 .await
                 .context("Fetch a piece failed")?;
            
@@ -192,32 +311,102 @@ async fn main() -> anyhow::Result<()> {
                 .await
                 .context("write out downloaded piece")?;
         },
-        Type::MagnetDownload{ output, magnet } => {
-            let (torrent,mut tcp_stream)  = utils::get_magnet_metadata(magnet)
+        Type::MagnetDownload{ output, magnet, files } => {
+            let (torrent, tcp_stream, _) = utils::get_magnet_metadata(magnet)
                 .await
                 .context("Failed to receive magnet meta data")?;
-
-            let message_to_send = Message {
-                message_tag: MessageTag::Interested,
-                payload: Payload::SimplePayload(Vec::new()),
-            };
-            let _message_sent = tcp_stream.send(message_to_send).await;
-           
-            let message_received = tcp_stream
-                .next()
+            // the metadata peer came from the magnet's tracker, which the swarm announces to as well
+            drop(tcp_stream);
+            let reserved = constant::get_reserved();
+            let events = Events::default();
+            let progress = show_progress(&events, arg.quiet);
+            let res = utils::download_torrent(torrent, output, None, reserved, events, limits(arg), files.as_deref())
                 .await
-                .expect("Expecting a unchoke")
-                .context("Message was invalid")?;
-            assert_eq!(message_received.message_tag, MessageTag::Unchoke);
-
-            let res: Vec<u8> = utils::fetch_all_pieces(&torrent, &mut tcp_stream)
-                .await
-                .context("Fetch all piece failed")?;
-           
-            tokio::fs::write(&output, res)
-                .await
-                .context("write all downloaded piece")?;
+                .context("Downloading all pieces");
+            finish_progress(progress).await;
+            res?;
         },
     }
     Ok(())
 }
+
+/// Renders the events of a download as a single progress line on stderr, nothing in `quiet` mode
+fn show_progress(events: &Events, quiet: bool) -> Option<JoinHandle<()>> {
+    if quiet {
+        return None;
+    }
+    let mut receiver: broadcast::Receiver<DownloadEvent> = events.subscribe();
+    Some(tokio::spawn(async move {
+        let mut progress = Progress::default();
+        loop {
+            match receiver.recv().await {
+                // printed above the progress line, which is drawn again below it
+                Ok(DownloadEvent::NotListening(reason)) => eprintln!("\r\x1b[KNot accepting incoming peers: {reason}"),
+                Ok(DownloadEvent::AcceptFailed(reason)) => eprintln!("\r\x1b[KAccepting incoming peers failed: {reason}"),
+                Ok(event) => progress.update(&event),
+                // a few events were skipped, the line is redrawn with the next one
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+            eprint!("\r{progress}\x1b[K");
+            if progress.completed {
+                break;
+            }
+        }
+        eprintln!();
+    }))
+}
+
+/// Prints what a seeding torrent reports, until its `Events` are dropped
+fn show_notices(events: &Events) -> JoinHandle<()> {
+    let mut receiver: broadcast::Receiver<DownloadEvent> = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(DownloadEvent::Seeding { have, pieces }) => println!("Seeding {have} of {pieces} pieces"),
+                Ok(DownloadEvent::NotListening(reason)) => eprintln!("Not accepting incoming peers: {reason}"),
+                Ok(DownloadEvent::AcceptFailed(reason)) => eprintln!("Accepting incoming peers failed: {reason}"),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Waits for the progress line to be finished, the download must have dropped its `Events` by now
+async fn finish_progress(progress: Option<JoinHandle<()>>) {
+    if let Some(progress) = progress {
+        let _ = progress.await;
+    }
+}
+
+/// The limits from the command line, with working hours a schedule keeps switching them
+fn limits(arg: &Args) -> Limits {
+    let kib = |rate: Option<u64>| rate.map(|rate| rate * 1024);
+    let global = Arc::new(RateLimit::new(kib(arg.download_rate), kib(arg.upload_rate)));
+    if let Some(hours) = &arg.working_hours {
+        let schedule = Schedule {
+            hours: hours.clone(),
+            utc_offset: arg.utc_offset,
+            // a working rate left out keeps the base rate, not unlimited
+            working: (
+                kib(arg.working_download_rate.or(arg.download_rate)),
+                kib(arg.working_upload_rate.or(arg.upload_rate)),
+            ),
+            other: (kib(arg.download_rate), kib(arg.upload_rate)),
+        };
+        let limit = global.clone();
+        tokio::spawn(async move { schedule.run(&limit).await });
+    }
+    Limits::new(global)
+}
+
+fn parse_hours(hours: &str) -> Result<Range<u8>, String> {
+    let (start, end) = hours.split_once('-').ok_or("Expected hours like 9-17")?;
+    let start: u8 = start.trim().parse().map_err(|e| format!("Start hour: {e}"))?;
+    let end: u8 = end.trim().parse().map_err(|e| format!("End hour: {e}"))?;
+    if start >= end || end > 24 {
+        return Err(format!("{start}-{end} is not a range of hours in a day"));
+    }
+    Ok(start..end)
+}
@@ -1,5 +1,3 @@
-use core::panic;
-
 use bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 use crate::{
@@ -8,7 +6,8 @@ use crate::{
     extension::{
         extensionhandshake::ExtensionHandshake, 
         extensionmetadata::{DataMetaData, ExtensionMetadata}, 
-        extensionpayload::{ExtensionPayload, ExtensionType}
+        extensionpayload::{ExtensionPayload, ExtensionType},
+        extensionpex::PexMessage
     }
 };
 
@@ -64,6 +63,17 @@ pub struct Message {
     pub payload: Payload,
 }
 
+impl Message {
+    /// Bytes the message takes on the wire, length prefix and tag included
+    pub fn wire_len(&self) -> usize {
+        let payload_length = match &self.payload {
+            Payload::SimplePayload(vector) => vector.len(),
+            Payload::ExtendedPayload(extension_payload_struct) => extension_payload_struct.to_vec().len(),
+        };
+        5 + payload_length
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Payload {
     SimplePayload(Vec<u8>),
@@ -74,6 +84,7 @@ pub enum Payload {
 pub struct MessageFramer;
 const MAX: usize = 2 * 16 * 1024; // 2^15
 const EXTNSION_ID: u8 = constant::get_extension_id();
+const PEX_EXTENSION_ID: u8 = constant::get_pex_extension_id();
 
 /// The 1st 4 bytes gives playload length + message_type
 impl Decoder for MessageFramer {
@@ -81,78 +92,105 @@ impl Decoder for MessageFramer {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // println!("DEcode lenght {}", src.len());
-        if src.len() < 5 {
-            // Not enough data to read length and tag marker.
-            return Ok(None);
-        }
+        // keep-alives and messages we do not understand are skipped until a message or the end of `src`
+        loop {
+            if src.len() < 4 {
+                // Not enough data to read length marker.
+                return Ok(None);
+            }
 
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
-        // println!("Payload lenght {}", length);
+            // Read length marker.
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
 
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if length > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
-            ));
-        }
+            if length == 0 {
+                // keep-alive, it only proves the peer is still there
+                src.advance(4);
+                continue;
+            }
 
-        if src.len() < 4 + length {
-            // The full string has not yet arrived.
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+            // Check that the length is not too large to avoid a denial of
+            // service attack where the server runs out of memory.
+            if length > MAX {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Frame of length {} is too large.", length),
+                ));
+            }
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
-        }
+            if src.len() < 4 + length {
+                // The full string has not yet arrived.
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + length - src.len());
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let data = if src.len() > 5 {
-            src[5..5 + length - 1].to_vec()
-        } else {
-            Vec::new()
-        };
-        // Convert the data to the appropriate Message
-        let message_tag = MessageTag::tag_to_type(&src[4]).expect("Invalid msg type");
-        src.advance(4 + length);
-        let mut payload = Payload::SimplePayload(data.clone());
-        if message_tag == MessageTag::Extension {
-            match data[0] {
-                0 => {
-                    let extension_handshake: ExtensionHandshake =
-                        serde_bencode::from_bytes(&data[1..]).expect("Serde bencode failed");
-                        payload = Payload::ExtendedPayload(ExtensionPayload { 
-                            extension_id: 0, 
-                            payload: ExtensionType::ExtensionHandshakeMessage(extension_handshake)  
-                    }); 
-                },
-                v if v == EXTNSION_ID => {
-                    let extension_metadata_data: DataMetaData = serde_bencode::from_bytes(&data[1..]).expect("Serde bencode failed");                   
-                    let i = &data[1..].len() - extension_metadata_data.total_size as usize + 1;
-                    let info : Info = serde_bencode::from_bytes(&data[i..]).expect("Conversion to Info failed");
-                    payload = Payload::ExtendedPayload(ExtensionPayload { 
-                        extension_id: 0, 
-                        payload: ExtensionType::MetaDataMessage(ExtensionMetadata::Data(extension_metadata_data, info))  
-                    }); 
-                },
-                _ => panic!("Unexpected extension payload")
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
+
+            // Use advance to modify src such that it no longer contains
+            // this frame.
+            let data = src[5..4 + length].to_vec();
+            // Convert the data to the appropriate Message
+            let message_tag = MessageTag::tag_to_type(&src[4]);
+            src.advance(4 + length);
+            let Some(message_tag) = message_tag else {
+                // messages from extensions we never negotiated, e.g. the fast extension, are skipped
+                continue;
             };
+            let mut payload = Payload::SimplePayload(data.clone());
+            if message_tag == MessageTag::Extension {
+                let Some(&extension_id) = data.first() else {
+                    return Err(invalid_data("Extension message without an id"));
+                };
+                match extension_id {
+                    0 => {
+                        let extension_handshake: ExtensionHandshake =
+                            serde_bencode::from_bytes(&data[1..]).map_err(invalid_data)?;
+                        payload = Payload::ExtendedPayload(ExtensionPayload {
+                            extension_id: 0,
+                            payload: ExtensionType::ExtensionHandshakeMessage(extension_handshake),
+                        });
+                    }
+                    v if v == EXTNSION_ID => {
+                        let extension_metadata_data: DataMetaData = serde_bencode::from_bytes(&data[1..]).map_err(invalid_data)?;
+                        let i = (data[1..].len() + 1)
+                            .checked_sub(extension_metadata_data.total_size as usize)
+                            .ok_or_else(|| invalid_data("Metadata piece shorter than its total size"))?;
+                        let info: Info = serde_bencode::from_bytes(&data[i..]).map_err(invalid_data)?;
+                        payload = Payload::ExtendedPayload(ExtensionPayload {
+                            extension_id: 0,
+                            payload: ExtensionType::MetaDataMessage(ExtensionMetadata::Data(extension_metadata_data, info)),
+                        });
+                    }
+                    v if v == PEX_EXTENSION_ID => {
+                        let pex: PexMessage = serde_bencode::from_bytes(&data[1..]).map_err(invalid_data)?;
+                        payload = Payload::ExtendedPayload(ExtensionPayload {
+                            extension_id: v,
+                            payload: ExtensionType::PexMessage(pex),
+                        });
+                    }
+                    // an extension we did not advertise
+                    _ => continue,
+                };
+            }
+            return Ok(Some(Message {
+                message_tag,
+                payload,
+            }));
         }
-        Ok(Some(Message {
-            message_tag,
-            payload,
-        }))
     }
 }
 
+fn invalid_data<E>(error: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
 impl Encoder<Message> for MessageFramer {
     type Error = std::io::Error;
 
@@ -190,6 +228,7 @@ impl Encoder<Message> for MessageFramer {
 }
 
 pub mod requestpayload{
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct RequestPayload {
         /// the zero-based piece index
         pub index: u32,
@@ -202,13 +241,23 @@ pub mod requestpayload{
     }
     
     impl RequestPayload {
+        /// Parses the payload of a `Request` or `Cancel` message, None unless it is 12 bytes
+        pub fn from_bytes(payload: &[u8]) -> Option<Self> {
+            let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().expect("slice length not 4"));
+            (payload.len() == 12).then(|| Self {
+                index: field(0),
+                begin: field(4),
+                length: field(8),
+            })
+        }
+
         pub fn to_vec(&self) -> Vec<u8> {
             // let mut vector: Vec<u8> = Vec::new();
             let mut buf: [u8; 12] = [0u8; 12];
             buf[0..4].copy_from_slice(&self.index.to_be_bytes());
             buf[4..8].copy_from_slice(&self.begin.to_be_bytes());
             buf[8..12].copy_from_slice(&self.length.to_be_bytes());
-            return buf.into();
+            buf.into()
         }
     }
     pub struct ReceivePayload {
@@ -226,11 +275,19 @@ pub mod requestpayload{
             let index = u32::from_be_bytes(payload[0..4].try_into().expect("slice length not 4"));
             let begin = u32::from_be_bytes(payload[4..8].try_into().expect("slice length not 4"));
             Self {
-                index: index,
-                begin: begin,
+                index,
+                begin,
                 block: payload.split_off(8),
             }
         }
+
+        pub fn to_vec(&self) -> Vec<u8> {
+            let mut buf = Vec::with_capacity(8 + self.block.len());
+            buf.extend_from_slice(&self.index.to_be_bytes());
+            buf.extend_from_slice(&self.begin.to_be_bytes());
+            buf.extend_from_slice(&self.block);
+            buf
+        }
     }
 
 }
@@ -279,7 +336,7 @@ mod tests {
         // Simulate a incomplete Bitfield message with payload
         // we want the total payload to be of 8 bytes but we make it 7 to simulate incompleteness
         let payload = vec![0xDE, 0xAD, 0xBE, 0xEF, 0xDE, 0xAD, 0xBE];
-        let length = 8 as u32 + 1;
+        let length = 8u32 + 1;
 
         let mut buf = BytesMut::new();
 
@@ -302,7 +359,7 @@ mod tests {
     #[test]
     fn test_my_message_decoder_3() {
         // Simulate a incomplete Bitfield message with just 4 bits
-        let length = 4 as u32;
+        let length = 4u32;
 
         let mut buf = BytesMut::new();
 
@@ -316,6 +373,46 @@ mod tests {
         assert_eq!(result, None::<Message>)
     }
 
+    #[test]
+    fn test_keep_alive_skipped() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        let mut decoder = MessageFramer {};
+        assert_eq!(decoder.decode(&mut buf).expect("Decoding failed"), None);
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        buf.extend_from_slice(&[0]);
+        let result = decoder.decode(&mut buf).expect("Decoding failed").expect("A message");
+        assert_eq!(result.message_tag, MessageTag::Choke);
+
+        // a flood of keep-alives must not grow the stack
+        (0..1_000_000).for_each(|_| buf.extend_from_slice(&0u32.to_be_bytes()));
+        assert_eq!(decoder.decode(&mut buf).expect("Decoding failed"), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_message_skipped() {
+        let mut buf = BytesMut::new();
+        // a fast extension "have all" we never negotiated, then an unchoke
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        buf.extend_from_slice(&[14]);
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        buf.extend_from_slice(&[1]);
+
+        let mut decoder = MessageFramer {};
+        let result = decoder.decode(&mut buf).expect("Decoding failed").expect("A message");
+        assert_eq!(result.message_tag, MessageTag::Unchoke);
+        assert!(buf.is_empty());
+
+        // a broken extension handshake is an error, not a panic
+        buf.extend_from_slice(&4u32.to_be_bytes());
+        buf.extend_from_slice(&[20, 0, b'd', b'x']);
+        assert!(decoder.decode(&mut buf).is_err());
+    }
+
     #[test]
     fn test_my_message_decoder_4() {
         // test to see if the 1st bit of the encoded codec is 0 
@@ -0,0 +1,362 @@
+//! One connection to a peer and the protocol state on both ends of it.
+//!
+//! Peers do not follow a script: a bitfield may never come, `Have`, `Choke` and
+//! `Unchoke` can arrive at any moment and keep-alives come in between. Every
+//! message goes through `recv`, which keeps the choke/interest flags, the
+//! peer's pieces and its requests up to date before handing the message on.
+
+use crate::{
+    bitfield::Bitfield,
+    constant,
+    extension::{
+        extensionhandshake::{ExtensionHandshake, M},
+        extensionpayload::{ExtensionPayload, ExtensionType},
+    },
+    handshake::supports_extensions,
+    message::{requestpayload::RequestPayload, Message, MessageFramer, MessageTag, Payload},
+    ratelimit::Limits,
+    utils::exchange_handshake,
+};
+use anyhow::Context;
+use futures_util::{sink::SinkExt, stream::StreamExt};
+use std::{collections::VecDeque, net::SocketAddr};
+use tokio::{net::TcpStream, time::Instant};
+use tokio_util::codec::Framed;
+
+/// Requests queued for one peer beyond this are dropped
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+
+pub struct PeerConnection {
+    stream: Framed<TcpStream, MessageFramer>,
+    addr: Option<SocketAddr>,
+    /// we refuse to upload to the peer
+    pub am_choking: bool,
+    /// we told the peer we want some of its pieces
+    pub am_interested: bool,
+    /// the peer refuses to upload to us, requests sent now are dropped
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// pieces the peer announced, empty until it sends a bitfield or a have
+    pub have: Bitfield,
+    /// pieces a bitfield added to `have` since someone last took them
+    pub new_bitfield: Option<Bitfield>,
+    /// pieces haves added to `have` since someone last took them
+    pub new_pieces: Vec<usize>,
+    /// both ends set the extension protocol bit in their handshakes
+    pub extensions: bool,
+    /// peers it told us about through PEX, until someone takes them
+    pub pex_peers: Vec<SocketAddr>,
+    /// where the peer accepts connections, IPv4 and IPv6, from its extension handshake
+    pub listen_addrs: Vec<SocketAddr>,
+    /// blocks the peer asked for and did not cancel, dropped whenever we choke it.
+    /// Only their order is kept, whether we can serve them is up to the caller.
+    pub requests: VecDeque<RequestPayload>,
+    /// bytes of blocks the peer sent us since someone last took them, see `Choker::downloaded`
+    pub downloaded: usize,
+    limits: Limits,
+    /// the download limit holds off reading the next message until then
+    paused_until: Option<Instant>,
+}
+
+impl PeerConnection {
+    /// Wraps a stream that completed the handshake, both sides start out choking and not interested
+    pub fn new(stream: Framed<TcpStream, MessageFramer>, num_of_pieces: usize) -> Self {
+        // an IPv4 peer reaching a dual stack listener has a mapped address, it is kept as IPv4
+        let addr = stream.get_ref().peer_addr().ok().map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+        Self {
+            stream,
+            addr,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            have: Bitfield::new(num_of_pieces),
+            new_bitfield: None,
+            new_pieces: Vec::new(),
+            extensions: false,
+            pex_peers: Vec::new(),
+            listen_addrs: Vec::new(),
+            requests: VecDeque::new(),
+            downloaded: 0,
+            limits: Limits::default(),
+            paused_until: None,
+        }
+    }
+
+    /// Every message from now on counts towards `limits`
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Connects and handshakes with `peer`
+    pub async fn connect(
+        info_hash: [u8; 20],
+        peer: &SocketAddr,
+        reserved: [u8; 8],
+        num_of_pieces: usize,
+    ) -> anyhow::Result<Self> {
+        let mut tcp_stream = TcpStream::connect(peer).await.context("TCP connection to peer")?;
+        let handshake = exchange_handshake(&mut tcp_stream, info_hash, reserved).await?;
+        let mut conn = Self::new(Framed::new(tcp_stream, MessageFramer), num_of_pieces);
+        conn.extensions = supports_extensions(&reserved) && supports_extensions(&handshake.reserved);
+        Ok(conn)
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// Sends any message, choke and interest changes are recorded
+    pub async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        match message.message_tag {
+            MessageTag::Choke => {
+                // a choked peer knows its requests are dropped
+                self.am_choking = true;
+                self.requests.clear();
+            }
+            MessageTag::Unchoke => self.am_choking = false,
+            MessageTag::Interested => self.am_interested = true,
+            MessageTag::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        let wait = self.limits.upload(message.wire_len());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        self.stream.send(message).await.context("Send message")
+    }
+
+    /// Tells the peer whether we want its pieces, nothing is sent when that did not change
+    pub async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        if interested == self.am_interested {
+            return Ok(());
+        }
+        let message_tag = match interested {
+            true => MessageTag::Interested,
+            false => MessageTag::NotInterested,
+        };
+        self.send(empty(message_tag)).await
+    }
+
+    /// Chokes or unchokes the peer, nothing is sent when that did not change
+    pub async fn set_choking(&mut self, choking: bool) -> anyhow::Result<()> {
+        if choking == self.am_choking {
+            return Ok(());
+        }
+        let message_tag = match choking {
+            true => MessageTag::Choke,
+            false => MessageTag::Unchoke,
+        };
+        self.send(empty(message_tag)).await
+    }
+
+    pub async fn request(&mut self, piece: usize, begin: u32, length: usize) -> anyhow::Result<()> {
+        self.send(block_message(MessageTag::Request, piece, begin, length)).await
+    }
+
+    /// Withdraws a block request, the peer may still send the block
+    pub async fn cancel(&mut self, piece: usize, begin: u32, length: usize) -> anyhow::Result<()> {
+        self.send(block_message(MessageTag::Cancel, piece, begin, length)).await
+    }
+
+    /// Next message from the peer, the connection state already reflects it.
+    /// Cancel safe, a message is only taken off the stream when it is returned.
+    pub async fn recv(&mut self) -> anyhow::Result<Message> {
+        // the previous message is paid for before reading on, so the peer is slowed down by TCP
+        if let Some(paused_until) = self.paused_until {
+            tokio::time::sleep_until(paused_until).await;
+            self.paused_until = None;
+        }
+        let message = self
+            .stream
+            .next()
+            .await
+            .context("Peer closed the connection")?
+            .context("Message was invalid")?;
+        let pause = self.limits.download(message.wire_len());
+        if !pause.is_zero() {
+            self.paused_until = Some(Instant::now() + pause);
+        }
+        match (&message.message_tag, &message.payload) {
+            (MessageTag::Choke, _) => self.peer_choking = true,
+            (MessageTag::Unchoke, _) => self.peer_choking = false,
+            (MessageTag::Interested, _) => self.peer_interested = true,
+            (MessageTag::NotInterested, _) => self.peer_interested = false,
+            // a peer does not lose pieces, a second bitfield only adds to the first
+            (MessageTag::Bitfield, Payload::SimplePayload(payload)) => {
+                let bitfield = Bitfield::from_payload(payload, self.have.len());
+                let added = self.new_bitfield.get_or_insert_with(|| Bitfield::new(bitfield.len()));
+                for piece in bitfield.pieces() {
+                    if !self.have.has(piece) {
+                        self.have.set(piece);
+                        added.set(piece);
+                    }
+                }
+            }
+            // a malformed have is ignored like any other piece we do not know
+            (MessageTag::Have, Payload::SimplePayload(payload)) if payload.len() == 4 => {
+                let piece = u32::from_be_bytes(payload[..4].try_into().expect("length checked"));
+                let piece = piece as usize;
+                if piece < self.have.len() && !self.have.has(piece) {
+                    self.have.set(piece);
+                    self.new_pieces.push(piece);
+                }
+            }
+            (MessageTag::Piece, Payload::SimplePayload(payload)) => self.downloaded += payload.len().saturating_sub(8),
+            // requests of a choked peer are dropped, it knows to send them again
+            (MessageTag::Request, Payload::SimplePayload(payload)) if !self.am_choking => {
+                if let Some(request) = RequestPayload::from_bytes(payload) {
+                    if self.requests.len() < MAX_QUEUED_REQUESTS && !self.requests.contains(&request) {
+                        self.requests.push_back(request);
+                    }
+                }
+            }
+            (MessageTag::Cancel, Payload::SimplePayload(payload)) => {
+                if let Some(cancel) = RequestPayload::from_bytes(payload) {
+                    self.requests.retain(|request| *request != cancel);
+                }
+            }
+            (
+                MessageTag::Extension,
+                Payload::ExtendedPayload(ExtensionPayload {
+                    payload: ExtensionType::PexMessage(pex),
+                    ..
+                }),
+            ) => self.pex_peers.extend(pex.added_peers()),
+            (
+                MessageTag::Extension,
+                Payload::ExtendedPayload(ExtensionPayload {
+                    payload: ExtensionType::ExtensionHandshakeMessage(handshake),
+                    ..
+                }),
+            ) => {
+                // the address we are connected to with its listen port, then the ones it gave
+                let connected = self.addr.filter(|_| handshake.p != 0).map(|addr| SocketAddr::new(addr.ip(), handshake.p));
+                self.listen_addrs.clear();
+                for addr in connected.into_iter().chain(handshake.ipv4_addr()).chain(handshake.ipv6_addr()) {
+                    if !self.listen_addrs.contains(&addr) {
+                        self.listen_addrs.push(addr);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    /// Tells the peer we understand PEX, only once both ends set the extension bit.
+    /// Peers then send the peers they know about, see `pex_peers`.
+    pub async fn send_extension_handshake(&mut self) -> anyhow::Result<()> {
+        if !self.extensions {
+            return Ok(());
+        }
+        let handshake = ExtensionHandshake::new(M {
+            ut_metadata: 0,
+            ut_pex: constant::get_pex_extension_id(),
+        });
+        self.send(Message {
+            message_tag: MessageTag::Extension,
+            payload: Payload::ExtendedPayload(ExtensionPayload {
+                extension_id: 0,
+                payload: ExtensionType::ExtensionHandshakeMessage(handshake),
+            }),
+        })
+        .await
+    }
+
+    /// Waits until the peer unchokes us, whatever else it sends meanwhile is recorded
+    pub async fn wait_for_unchoke(&mut self) -> anyhow::Result<()> {
+        while self.peer_choking {
+            self.recv().await?;
+        }
+        Ok(())
+    }
+}
+
+fn empty(message_tag: MessageTag) -> Message {
+    Message {
+        message_tag,
+        payload: Payload::SimplePayload(Vec::new()),
+    }
+}
+
+fn block_message(message_tag: MessageTag, piece: usize, begin: u32, length: usize) -> Message {
+    let payload = RequestPayload {
+        index: piece as u32,
+        begin,
+        length: length as u32,
+    };
+    Message {
+        message_tag,
+        payload: Payload::SimplePayload(payload.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn test_messages_in_any_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            // no bitfield, a keep-alive, then haves around a choke
+            peer.get_mut().write_all(&[0, 0, 0, 0]).await.unwrap();
+            peer.send(empty(MessageTag::Unchoke)).await.unwrap();
+            let have = |piece: u32| Message {
+                message_tag: MessageTag::Have,
+                payload: Payload::SimplePayload(piece.to_be_bytes().to_vec()),
+            };
+            peer.send(have(3)).await.unwrap();
+            peer.send(empty(MessageTag::Choke)).await.unwrap();
+            peer.send(have(9)).await.unwrap();
+            peer.send(empty(MessageTag::Interested)).await.unwrap();
+            peer.send(empty(MessageTag::Unchoke)).await.unwrap();
+            // keep the connection open until the other end is done
+            let _ = peer.next().await;
+        });
+
+        let stream = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let mut conn = PeerConnection::new(stream, 4);
+        assert!(conn.peer_choking && conn.am_choking);
+        assert_eq!(conn.recv().await.unwrap().message_tag, MessageTag::Unchoke);
+        conn.recv().await.unwrap();
+        assert!(conn.have.has(3));
+        conn.recv().await.unwrap();
+        assert!(conn.peer_choking);
+        conn.wait_for_unchoke().await.unwrap();
+        // the out of range have was ignored, the interest was recorded
+        assert_eq!(conn.have.count(), 1);
+        assert_eq!(conn.new_pieces, vec![3]);
+        assert!(conn.peer_interested && !conn.peer_choking);
+        conn.set_interested(true).await.unwrap();
+        assert!(conn.am_interested);
+    }
+
+    #[tokio::test]
+    async fn test_listen_addrs_from_extension_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            let mut handshake = ExtensionHandshake::new(M { ut_metadata: 0, ut_pex: 1 });
+            handshake.p = 7000;
+            handshake.ipv4 = "10.0.0.1".parse().unwrap();
+            handshake.ipv6 = "2001:db8::1".parse().unwrap();
+            let payload = ExtensionPayload { extension_id: 0, payload: ExtensionType::ExtensionHandshakeMessage(handshake) };
+            peer.send(Message { message_tag: MessageTag::Extension, payload: Payload::ExtendedPayload(payload) }).await.unwrap();
+            let _ = peer.next().await;
+        });
+
+        let stream = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let mut conn = PeerConnection::new(stream, 4);
+        conn.recv().await.unwrap();
+        let expected: Vec<SocketAddr> = ["127.0.0.1:7000", "10.0.0.1:7000", "[2001:db8::1]:7000"].iter().map(|addr| addr.parse().unwrap()).collect();
+        assert_eq!(conn.listen_addrs, expected);
+    }
+}
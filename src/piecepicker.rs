@@ -0,0 +1,213 @@
+//! Choosing which piece to download next
+
+use crate::bitfield::Bitfield;
+use rand::{seq::IteratorRandom, Rng};
+use std::collections::BTreeSet;
+
+/// Decides which piece a peer should download next.
+///
+/// The swarm reports every bitfield, have and departing peer so a picker can keep
+/// track of piece availability, then asks it to `pick` among the pieces nobody is
+/// downloading yet. A piece is reported once per peer, through `peer_bitfield` or
+/// `peer_have`. Implement it to plug in your own strategy.
+pub trait PiecePicker: Send {
+    /// A peer announced all of its pieces, a second bitfield only holds the pieces it adds
+    fn peer_bitfield(&mut self, _bitfield: &Bitfield) {}
+
+    /// A peer announced a piece it just finished
+    fn peer_have(&mut self, _piece: usize) {}
+
+    /// A peer left, `bitfield` is everything it had
+    fn peer_gone(&mut self, _bitfield: &Bitfield) {}
+
+    /// A piece was downloaded and verified
+    fn piece_completed(&mut self, _piece: usize) {}
+
+    /// Chooses one of the `pending` pieces the peer has, None if it has none of them
+    fn pick(&mut self, peer: &Bitfield, pending: &BTreeSet<usize>) -> Option<usize>;
+}
+
+/// How many connected peers have each piece
+#[derive(Debug, Clone, Default)]
+pub struct Availability(Vec<u32>);
+
+impl Availability {
+    pub fn add(&mut self, bitfield: &Bitfield) {
+        bitfield.pieces().for_each(|piece| self.have(piece));
+    }
+
+    pub fn have(&mut self, piece: usize) {
+        if piece >= self.0.len() {
+            self.0.resize(piece + 1, 0);
+        }
+        self.0[piece] += 1;
+    }
+
+    pub fn remove(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.pieces() {
+            if let Some(count) = self.0.get_mut(piece) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn get(&self, piece: usize) -> u32 {
+        self.0.get(piece).copied().unwrap_or_default()
+    }
+}
+
+/// Downloads the pieces fewest peers have first, ties are broken at random
+/// so peers starting at the same time do not all fetch the same piece
+#[derive(Debug, Default)]
+pub struct RarestFirst {
+    availability: Availability,
+}
+
+impl PiecePicker for RarestFirst {
+    fn peer_bitfield(&mut self, bitfield: &Bitfield) {
+        self.availability.add(bitfield);
+    }
+
+    fn peer_have(&mut self, piece: usize) {
+        self.availability.have(piece);
+    }
+
+    fn peer_gone(&mut self, bitfield: &Bitfield) {
+        self.availability.remove(bitfield);
+    }
+
+    fn pick(&mut self, peer: &Bitfield, pending: &BTreeSet<usize>) -> Option<usize> {
+        let mut rng = rand::thread_rng();
+        let mut rarest = None;
+        let mut ties = 0;
+        for piece in pending.iter().copied().filter(|piece| peer.has(*piece)) {
+            let count = self.availability.get(piece);
+            match rarest {
+                Some((_, min)) if count > min => continue,
+                Some((_, min)) if count == min => {
+                    // reservoir sampling keeps every tied piece equally likely
+                    ties += 1;
+                    if rng.gen_range(0..ties) == 0 {
+                        rarest = Some((piece, count));
+                    }
+                }
+                _ => {
+                    ties = 1;
+                    rarest = Some((piece, count));
+                }
+            }
+        }
+        rarest.map(|(piece, _)| piece)
+    }
+}
+
+/// Downloads pieces in index order, useful for streaming
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, peer: &Bitfield, pending: &BTreeSet<usize>) -> Option<usize> {
+        pending.iter().copied().find(|piece| peer.has(*piece))
+    }
+}
+
+/// Picks random pieces until a few are complete, then switches to rarest first.
+/// A new peer gets something to share quickly instead of waiting on the rarest, often slowest, pieces.
+#[derive(Debug)]
+pub struct RandomFirst {
+    random_pieces: usize,
+    completed: usize,
+    rarest: RarestFirst,
+}
+
+impl Default for RandomFirst {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl RandomFirst {
+    /// Picks at random until `random_pieces` pieces completed
+    pub fn new(random_pieces: usize) -> Self {
+        Self {
+            random_pieces,
+            completed: 0,
+            rarest: RarestFirst::default(),
+        }
+    }
+}
+
+impl PiecePicker for RandomFirst {
+    fn peer_bitfield(&mut self, bitfield: &Bitfield) {
+        self.rarest.peer_bitfield(bitfield);
+    }
+
+    fn peer_have(&mut self, piece: usize) {
+        self.rarest.peer_have(piece);
+    }
+
+    fn peer_gone(&mut self, bitfield: &Bitfield) {
+        self.rarest.peer_gone(bitfield);
+    }
+
+    fn piece_completed(&mut self, piece: usize) {
+        self.completed += 1;
+        self.rarest.piece_completed(piece);
+    }
+
+    fn pick(&mut self, peer: &Bitfield, pending: &BTreeSet<usize>) -> Option<usize> {
+        if self.completed >= self.random_pieces {
+            return self.rarest.pick(peer, pending);
+        }
+        pending
+            .iter()
+            .copied()
+            .filter(|piece| peer.has(*piece))
+            .choose(&mut rand::thread_rng())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(6);
+        pieces.iter().for_each(|piece| bitfield.set(*piece));
+        bitfield
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut picker = RarestFirst::default();
+        picker.peer_bitfield(&bitfield(&[0, 1, 2, 3]));
+        picker.peer_bitfield(&bitfield(&[0, 1, 2]));
+        picker.peer_have(3);
+        picker.peer_have(4);
+        let pending: BTreeSet<usize> = (0..6).collect();
+        let seeder = Bitfield::full(6);
+        assert_eq!(picker.pick(&seeder, &pending), Some(5));
+        assert_eq!(picker.pick(&bitfield(&[0, 1, 4]), &pending), Some(4));
+        assert_eq!(picker.pick(&bitfield(&[5]), &BTreeSet::from([0, 1])), None);
+
+        // the two equally rare pieces are both picked eventually
+        let pending = BTreeSet::from([0, 3]);
+        let picks: BTreeSet<_> = (0..64).filter_map(|_| picker.pick(&seeder, &pending)).collect();
+        assert_eq!(picks, pending);
+
+        picker.peer_gone(&bitfield(&[1]));
+        assert_eq!(picker.pick(&seeder, &BTreeSet::from([1, 2])), Some(1));
+    }
+
+    #[test]
+    fn test_sequential_and_random_first() {
+        let pending = BTreeSet::from([2, 3, 5]);
+        assert_eq!(Sequential.pick(&bitfield(&[3, 5]), &pending), Some(3));
+
+        let mut picker = RandomFirst::new(1);
+        picker.peer_bitfield(&bitfield(&[2, 3]));
+        assert!(pending.contains(&picker.pick(&Bitfield::full(6), &pending).unwrap()));
+        picker.piece_completed(0);
+        assert_eq!(picker.pick(&Bitfield::full(6), &pending), Some(5));
+    }
+}
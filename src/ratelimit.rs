@@ -0,0 +1,227 @@
+//! Capping download and upload rates.
+//!
+//! A `RateLimit` holds one token bucket per direction. Every message a
+//! `PeerConnection` sends or receives takes its size in bytes from the buckets
+//! of the connection's `Limits`: the session wide limit and the limit of its
+//! torrent, see `Swarm::torrent_limit` and `Seeder::torrent_limit`. Limits can
+//! be changed while connections use them, a `Schedule` does so on its own at
+//! the start and end of working hours.
+
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+/// How often a `Schedule` checks whether working hours began or ended
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bytes per second through one direction, `None` is unlimited.
+/// Up to a second's worth of unused tokens are saved up for bursts.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: Option<u64>,
+    /// negative when messages larger than the tokens left went through
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.unwrap_or_default() as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().expect("bucket lock poisoned").rate
+    }
+
+    /// Takes effect for the next message, tokens saved up beyond the new burst are dropped
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().expect("bucket lock poisoned");
+        state.refill();
+        state.rate = rate;
+        state.tokens = state.tokens.min(rate.unwrap_or_default() as f64);
+    }
+
+    /// Takes `bytes` tokens and returns how long to wait until they were available
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().expect("bucket lock poisoned");
+        state.refill();
+        let Some(rate) = state.rate.filter(|rate| *rate > 0) else {
+            return Duration::ZERO;
+        };
+        state.tokens -= bytes as f64;
+        match state.tokens < 0.0 {
+            true => Duration::from_secs_f64(-state.tokens / rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let earned = now.duration_since(self.updated).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + earned).min(rate as f64);
+        }
+        self.updated = now;
+    }
+}
+
+/// Download and upload limit of a session or a torrent
+#[derive(Debug)]
+pub struct RateLimit {
+    pub download: TokenBucket,
+    pub upload: TokenBucket,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl RateLimit {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: TokenBucket::new(download),
+            upload: TokenBucket::new(upload),
+        }
+    }
+
+    pub fn set(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+/// Every limit one connection is subject to, unlimited by default
+#[derive(Debug, Clone, Default)]
+pub struct Limits(Vec<Arc<RateLimit>>);
+
+impl Limits {
+    /// Limits shared by every torrent of the session
+    pub fn new(global: Arc<RateLimit>) -> Self {
+        Self(vec![global])
+    }
+
+    /// These limits plus one that applies to a single torrent
+    pub fn with_torrent(&self, torrent: Arc<RateLimit>) -> Self {
+        let mut limits = self.0.clone();
+        limits.push(torrent);
+        Self(limits)
+    }
+
+    /// Counts `bytes` received, returns how long to pause before receiving more.
+    /// Tokens are taken from every bucket at once, the slowest one decides the pause.
+    pub fn download(&self, bytes: usize) -> Duration {
+        self.0.iter().map(|limit| limit.download.take(bytes)).max().unwrap_or_default()
+    }
+
+    /// Counts `bytes` about to be sent, returns how long to wait before sending them
+    pub fn upload(&self, bytes: usize) -> Duration {
+        self.0.iter().map(|limit| limit.upload.take(bytes)).max().unwrap_or_default()
+    }
+}
+
+/// Different limits during working hours, Monday to Friday
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// hours of the day, in local time
+    pub hours: Range<u8>,
+    /// local time minus UTC, in minutes
+    pub utc_offset: i32,
+    /// download and upload rate during working hours
+    pub working: (Option<u64>, Option<u64>),
+    /// download and upload rate the rest of the time
+    pub other: (Option<u64>, Option<u64>),
+}
+
+impl Schedule {
+    /// The rates that apply `since_epoch` after the Unix epoch
+    pub fn rates_at(&self, since_epoch: Duration) -> (Option<u64>, Option<u64>) {
+        let local = since_epoch.as_secs() as i64 + self.utc_offset as i64 * 60;
+        let days = local.div_euclid(86_400);
+        let hour = (local.rem_euclid(86_400) / 3600) as u8;
+        // 1 January 1970 was a Thursday, 0 is Monday
+        let weekday = (days + 3).rem_euclid(7);
+        match weekday < 5 && self.hours.contains(&hour) {
+            true => self.working,
+            false => self.other,
+        }
+    }
+
+    /// Keeps `limit` at the rates for the current time until the task is dropped
+    pub async fn run(&self, limit: &RateLimit) {
+        let mut check = tokio::time::interval(SCHEDULE_INTERVAL);
+        loop {
+            check.tick().await;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let (download, upload) = self.rates_at(now);
+            if limit.download.rate() != download || limit.upload.rate() != upload {
+                limit.set(download, upload);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Equal but for the little time that passed between the calls
+    fn assert_about(wait: Duration, expected: Duration) {
+        assert!(wait.abs_diff(expected) < Duration::from_millis(50), "{wait:?} is not {expected:?}");
+    }
+
+    #[tokio::test]
+    async fn test_limits_pace_transfers() {
+        let global = Arc::new(RateLimit::new(Some(10_000), None));
+        let torrent = Arc::new(RateLimit::new(None, Some(5_000)));
+        let limits = Limits::new(global.clone()).with_torrent(torrent);
+
+        // a full bucket lets the first second's worth through at once, more has to wait
+        assert_about(limits.download(10_000), Duration::ZERO);
+        assert_about(limits.download(20_000), Duration::from_secs(2));
+        assert_about(limits.download(1_000), Duration::from_millis(2100));
+        // the torrent limit applies to uploads, the global one does not
+        assert_about(limits.upload(15_000), Duration::from_secs(2));
+
+        global.set(None, None);
+        assert_eq!(limits.download(1_000_000), Duration::ZERO);
+
+        // tokens saved up while idle never exceed one second's worth
+        let bucket = TokenBucket::new(Some(1_000));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_about(bucket.take(1_500), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_schedule_working_hours() {
+        let schedule = Schedule {
+            hours: 9..17,
+            utc_offset: 120,
+            working: (Some(100), Some(10)),
+            other: (None, None),
+        };
+        // Monday 5 January 1970, 7:30 UTC is 9:30 local time
+        let monday = Duration::from_secs(4 * 86_400);
+        assert_eq!(schedule.rates_at(monday + Duration::from_secs(7 * 3600 + 1800)), (Some(100), Some(10)));
+        assert_eq!(schedule.rates_at(monday + Duration::from_secs(15 * 3600)), (None, None));
+        // Saturday
+        assert_eq!(schedule.rates_at(monday + Duration::from_secs(5 * 86_400 + 8 * 3600)), (None, None));
+    }
+}
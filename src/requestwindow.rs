@@ -0,0 +1,108 @@
+//! How many block requests are kept in flight with a single peer
+
+use crate::extension::extensionhandshake::ExtensionHandshake;
+use std::time::Duration;
+
+/// Blocks are always requested in 16 KiB chunks
+pub const BLOCK_SIZE: usize = 16 * 1024;
+/// Peers that do not advertise `reqq` get a conservative window
+pub const DEFAULT_WINDOW: usize = 5;
+/// Never pipeline fewer than this many requests
+const MIN_WINDOW: usize = 2;
+
+/// Window of outstanding requests for one peer.
+///
+/// An adaptive window starts at the peer's `reqq` and is resized from the measured
+/// block latency and arrival rate so that just enough requests are in flight to cover
+/// one round trip, it never grows past `reqq`.
+#[derive(Debug, Clone)]
+pub struct RequestWindow {
+    size: usize,
+    max: usize,
+    adaptive: bool,
+    /// lowest request to block latency seen, approximates the round trip without queueing
+    min_rtt: Option<Duration>,
+    /// smoothed time between two blocks arriving
+    gap: Option<Duration>,
+}
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+impl RequestWindow {
+    /// Adaptive window capped at `max` outstanding requests
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            size: max,
+            max,
+            adaptive: true,
+            min_rtt: None,
+            gap: None,
+        }
+    }
+
+    /// Window that always keeps exactly `size` requests outstanding
+    pub fn fixed(size: usize) -> Self {
+        Self {
+            adaptive: false,
+            ..Self::new(size)
+        }
+    }
+
+    /// Adaptive window sized from the `reqq` of the peer's extension handshake
+    pub fn from_handshake(handshake: &ExtensionHandshake) -> Self {
+        match handshake.reqq {
+            0 => Self::default(),
+            reqq => Self::new(reqq as usize),
+        }
+    }
+
+    /// Number of requests that may be outstanding right now
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Records a block that took `rtt` from request to arrival and
+    /// arrived `gap` after the previous one
+    pub fn record(&mut self, rtt: Duration, gap: Option<Duration>) {
+        if !self.adaptive {
+            return;
+        }
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        if let Some(sample) = gap {
+            // same smoothing factor TCP uses for its round trip estimate
+            self.gap = Some(self.gap.map_or(sample, |gap| (gap * 7 + sample) / 8));
+        }
+        if let (Some(min_rtt), Some(gap)) = (self.min_rtt, self.gap) {
+            // bandwidth delay product in blocks, plus headroom so the pipe never drains
+            let in_flight = min_rtt.as_secs_f64() / gap.as_secs_f64().max(1e-6);
+            self.size = (in_flight.ceil() as usize + MIN_WINDOW).clamp(MIN_WINDOW.min(self.max), self.max);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_adapts_to_latency() {
+        let mut window = RequestWindow::new(250);
+        assert_eq!(window.size(), 250);
+        // 10ms round trip, a block every millisecond: ~10 blocks in flight
+        window.record(Duration::from_millis(10), None);
+        for _ in 0..50 {
+            window.record(Duration::from_millis(40), Some(Duration::from_millis(1)));
+        }
+        assert_eq!(window.size(), 12);
+
+        let mut fixed = RequestWindow::fixed(3);
+        fixed.record(Duration::from_millis(10), Some(Duration::from_millis(1)));
+        assert_eq!(fixed.size(), 3);
+        assert_eq!(RequestWindow::new(1).size(), 1);
+    }
+}
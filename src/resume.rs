@@ -0,0 +1,253 @@
+//! Resume state of an interrupted download.
+//!
+//! Blocks are written to the output file(s) as they arrive and a bencoded resume
+//! file next to the output records which pieces verified and which blocks of the
+//! other pieces are already on disk. A restarted download checks the recorded
+//! pieces against the data on disk and only fetches what is missing.
+//!
+//! Writes happen on tokio's blocking threads, the download's tasks only wait for them.
+
+use crate::{bitfield::Bitfield, requestwindow::BLOCK_SIZE, storage::Storage, torrent::Torrent};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::task::spawn_blocking;
+
+/// The resume file is rewritten after this many blocks arrived, and after every verified piece
+const SAVE_EVERY_BLOCKS: usize = 32;
+
+/// What the resume file holds
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResumeData {
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    /// bitfield of the verified pieces
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    /// blocks on disk for pieces that did not verify yet
+    #[serde(default)]
+    pub partial: Vec<PartialPiece>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartialPiece {
+    pub piece: usize,
+    /// one bit per 16 KiB block
+    #[serde(with = "serde_bytes")]
+    pub blocks: Vec<u8>,
+}
+
+/// Download progress of one torrent, kept in sync with `<output>.resume`
+#[derive(Debug)]
+pub struct Resume {
+    torrent: Arc<Torrent>,
+    storage: Arc<Storage>,
+    path: PathBuf,
+    state: Mutex<ResumeState>,
+    /// held while the resume file is rewritten, so an older state never replaces a newer one
+    saving: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug)]
+struct ResumeState {
+    verified: Bitfield,
+    blocks: HashMap<usize, Bitfield>,
+    unsaved: usize,
+}
+
+impl Resume {
+    /// Loads the resume file next to the storage's output when there is one for this torrent.
+    /// Recorded pieces that no longer match the data on disk are downloaded again.
+    pub fn open(torrent: Arc<Torrent>, storage: Arc<Storage>) -> anyhow::Result<Self> {
+        let num_of_pieces = torrent.info.num_pieces();
+        let mut path = storage.output().as_os_str().to_owned();
+        path.push(".resume");
+        let resume = Self {
+            path: PathBuf::from(path),
+            storage,
+            state: Mutex::new(ResumeState {
+                verified: Bitfield::new(num_of_pieces),
+                blocks: HashMap::new(),
+                unsaved: 0,
+            }),
+            saving: tokio::sync::Mutex::new(()),
+            torrent,
+        };
+        let data = match fs::read(&resume.path) {
+            Ok(content) => serde_bencode::from_bytes::<ResumeData>(&content).context("Parse resume file")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(resume),
+            Err(e) => return Err(e).context("Read resume file"),
+        };
+        if data.info_hash != resume.torrent.info_hash() {
+            // left over from another torrent, start from scratch
+            return Ok(resume);
+        }
+
+        let recorded = Bitfield::from_payload(&data.pieces, num_of_pieces);
+        let verified = recorded.pieces().filter(|piece| resume.check_piece(*piece)).collect::<Vec<_>>();
+        let mut state = resume.state.lock().expect("resume lock poisoned");
+        verified.into_iter().for_each(|piece| state.verified.set(piece));
+        for partial in data.partial {
+            if partial.piece >= num_of_pieces || state.verified.has(partial.piece) {
+                continue;
+            }
+            let blocks = Bitfield::from_payload(&partial.blocks, num_of_blocks(&resume.torrent, partial.piece));
+            state.blocks.insert(partial.piece, blocks);
+        }
+        drop(state);
+        Ok(resume)
+    }
+
+    /// Pieces verified in this or an earlier run
+    pub fn verified(&self) -> Bitfield {
+        self.state.lock().expect("resume lock poisoned").verified.clone()
+    }
+
+    /// Where the pieces are written, verified ones can be uploaded from there
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Blocks of an unverified piece that are already on disk, as `(begin, block)`
+    pub fn stored_blocks(&self, piece: usize) -> Vec<(u32, Vec<u8>)> {
+        let Some(blocks) = self.state.lock().expect("resume lock poisoned").blocks.get(&piece).cloned() else {
+            return Vec::new();
+        };
+        let piece_size = self.torrent.info.piece_size(piece);
+        blocks
+            .pieces()
+            .filter_map(|block| {
+                let begin = block * BLOCK_SIZE;
+                let length = BLOCK_SIZE.min(piece_size - begin);
+                let data = self.storage.read_at(self.piece_offset(piece) + begin, length).ok()?;
+                Some((begin as u32, data))
+            })
+            .collect()
+    }
+
+    /// Writes a block that just arrived and records it
+    pub async fn block_received(&self, piece: usize, begin: u32, block: Vec<u8>) -> anyhow::Result<()> {
+        let (storage, offset) = (self.storage.clone(), self.piece_offset(piece) + begin as usize);
+        spawn_blocking(move || storage.write_at(offset, &block)).await.context("Write block")??;
+        let save = {
+            let mut state = self.state.lock().expect("resume lock poisoned");
+            let blocks = num_of_blocks(&self.torrent, piece);
+            state
+                .blocks
+                .entry(piece)
+                .or_insert_with(|| Bitfield::new(blocks))
+                .set(begin as usize / BLOCK_SIZE);
+            state.unsaved += 1;
+            state.unsaved >= SAVE_EVERY_BLOCKS
+        };
+        if save {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Records a verified piece, the piece is written out again unless all of its blocks already were
+    pub async fn piece_verified(&self, piece: usize, data: Vec<u8>) -> anyhow::Result<()> {
+        let on_disk = {
+            let mut state = self.state.lock().expect("resume lock poisoned");
+            state.blocks.remove(&piece).is_some_and(|blocks| blocks.is_complete())
+        };
+        if !on_disk {
+            let storage = self.storage.clone();
+            spawn_blocking(move || storage.write_piece(piece, &data)).await.context("Write piece")??;
+        }
+        // only now, uploads and readers read the piece from disk once it counts as verified
+        self.state.lock().expect("resume lock poisoned").verified.set(piece);
+        self.save().await
+    }
+
+    /// Forgets the blocks of a piece, e.g. after it failed verification
+    pub fn discard(&self, piece: usize) {
+        self.state.lock().expect("resume lock poisoned").blocks.remove(&piece);
+    }
+
+    /// Rewrites the resume file
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let _saving = self.saving.lock().await;
+        let content = self.encode()?;
+        // write next to it and rename so a crash never leaves half a resume file
+        let path = self.path.clone();
+        let temporary = self.path.with_extension("resume.tmp");
+        spawn_blocking(move || {
+            fs::write(&temporary, content).context("Write resume file")?;
+            fs::rename(&temporary, &path).context("Replace resume file")
+        })
+        .await
+        .context("Save resume file")?
+    }
+
+    /// The resume file's content for the current state, which then counts as saved
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut state = self.state.lock().expect("resume lock poisoned");
+        let data = ResumeData {
+            info_hash: self.torrent.info_hash().to_vec(),
+            pieces: state.verified.as_bytes().to_vec(),
+            partial: state
+                .blocks
+                .iter()
+                .map(|(piece, blocks)| PartialPiece {
+                    piece: *piece,
+                    blocks: blocks.as_bytes().to_vec(),
+                })
+                .collect(),
+        };
+        let content = serde_bencode::to_bytes(&data).context("Encode resume file")?;
+        state.unsaved = 0;
+        Ok(content)
+    }
+
+    fn check_piece(&self, piece: usize) -> bool {
+        self.storage.check_piece(piece, &self.torrent.info.pieces.0[piece])
+    }
+
+    fn piece_offset(&self, piece: usize) -> usize {
+        piece * self.torrent.info.pieces_length
+    }
+}
+
+fn num_of_blocks(torrent: &Torrent, piece: usize) -> usize {
+    torrent.info.piece_size(piece).div_ceil(BLOCK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::single_file_torrent;
+
+    #[tokio::test]
+    async fn test_resume_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out").to_string_lossy().into_owned();
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..3 * piece_length as u32).map(|i| (i % 7) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, piece_length));
+
+        let storage = Arc::new(Storage::new(&tor.info, &output).unwrap());
+        let resume = Resume::open(tor.clone(), storage.clone()).expect("Fresh resume");
+        resume.piece_verified(0, data[..piece_length].to_vec()).await.unwrap();
+        resume.piece_verified(2, data[2 * piece_length..].to_vec()).await.unwrap();
+        resume.block_received(1, BLOCK_SIZE as u32, data[piece_length + BLOCK_SIZE..2 * piece_length].to_vec()).await.unwrap();
+        resume.save().await.unwrap();
+        drop(resume);
+
+        // piece 2 got corrupted on disk while we were not running
+        storage.write_at(2 * piece_length, b"garbage").unwrap();
+
+        let resume = Resume::open(tor, storage).expect("Resume file");
+        assert_eq!(resume.verified().pieces().collect::<Vec<_>>(), vec![0]);
+        let blocks = resume.stored_blocks(1);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, BLOCK_SIZE as u32);
+        assert_eq!(blocks[0].1, &data[piece_length + BLOCK_SIZE..2 * piece_length]);
+    }
+}
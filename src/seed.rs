@@ -0,0 +1,360 @@
+//! Uploading the pieces we have.
+//!
+//! The `Choker` decides which interested peers are unchoked. Their requests are
+//! queued by the `PeerConnection` and answered from the data on disk one block at
+//! a time. Messages that already arrived are handled before each block goes out,
+//! so a `Cancel` still catches the block it is meant for. The `Swarm` uploads the
+//! same way while it downloads. Which peers are connected to, and how often a
+//! failed one is tried again, is up to a `ConnectionManager` as in the `Swarm`.
+
+use crate::{
+    bitfield::Bitfield,
+    choker::Choker,
+    connectionmanager::{ConnectionManager, ConnectionSlots, PeerSource},
+    message::{
+        requestpayload::{ReceivePayload, RequestPayload},
+        Message, MessageTag, Payload,
+    },
+    peerconnection::PeerConnection,
+    ratelimit::{Limits, RateLimit},
+    storage::Storage,
+    swarm::MAX_PEERS,
+    torrent::Torrent,
+    tracker::TransferStats,
+};
+use anyhow::Context;
+use futures_util::FutureExt;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinSet, time::timeout};
+
+/// A peer that sends nothing for this long is dropped
+const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Serves the verified pieces of one torrent from its storage
+pub struct Seeder {
+    torrent: Arc<Torrent>,
+    storage: Arc<Storage>,
+    have: Bitfield,
+    stats: Arc<TransferStats>,
+    choker: Choker,
+    limits: Limits,
+    /// applies to this torrent only, on top of `limits`
+    torrent_limit: Arc<RateLimit>,
+    slots: ConnectionSlots,
+    /// upper bound on simultaneous peer connections
+    pub max_peers: usize,
+}
+
+impl Seeder {
+    /// Checks every piece in `storage`, only the ones matching their hash are served
+    pub fn new(torrent: Arc<Torrent>, storage: Arc<Storage>, stats: Arc<TransferStats>) -> Self {
+        let num_of_pieces = torrent.info.num_pieces();
+        let mut have = Bitfield::new(num_of_pieces);
+        (0..num_of_pieces)
+            .filter(|piece| storage.check_piece(*piece, &torrent.info.pieces.0[*piece]))
+            .for_each(|piece| have.set(piece));
+        Self::with_pieces(torrent, storage, have, stats)
+    }
+
+    /// Serves the pieces in `have`, they are trusted to be verified already
+    pub fn with_pieces(torrent: Arc<Torrent>, storage: Arc<Storage>, have: Bitfield, stats: Arc<TransferStats>) -> Self {
+        Self {
+            torrent,
+            storage,
+            have,
+            stats,
+            choker: Choker::default(),
+            limits: Limits::default(),
+            torrent_limit: Arc::new(RateLimit::default()),
+            slots: ConnectionSlots::default(),
+            max_peers: MAX_PEERS,
+        }
+    }
+
+    /// Replaces the default upload slots
+    pub fn with_choker(mut self, choker: Choker) -> Self {
+        self.choker = choker;
+        self
+    }
+
+    /// Every peer served counts towards `limits`
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Connections count towards `slots` shared with other torrents, on top of `max_peers`
+    pub fn with_slots(mut self, slots: ConnectionSlots) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// The limit of this torrent alone, unlimited until it is set, it can be changed while seeding
+    pub fn torrent_limit(&self) -> Arc<RateLimit> {
+        self.torrent_limit.clone()
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// Connects to each peer and serves it until it leaves. Peers arriving on
+    /// `more_peers` and peers connecting to us on `incoming` are served as well,
+    /// returns once both are closed and every peer is gone, peers waiting to be tried
+    /// again are given up then. A peer announced again while we are connected to it
+    /// is left alone.
+    pub async fn seed(
+        self: Arc<Self>,
+        peers: Vec<SocketAddr>,
+        mut more_peers: Option<&mut mpsc::UnboundedReceiver<Vec<SocketAddr>>>,
+        mut incoming: Option<mpsc::UnboundedReceiver<PeerConnection>>,
+        reserved: [u8; 8],
+    ) -> anyhow::Result<()> {
+        // nothing is downloaded here, peers are ranked by what they take
+        let rechoke = self.choker.run(|| true);
+        tokio::pin!(rechoke);
+        let mut tasks: JoinSet<(SocketAddr, anyhow::Result<()>)> = JoinSet::new();
+        let manager = Arc::new(ConnectionManager::new(self.slots.clone(), self.max_peers));
+        manager.add(peers, PeerSource::Tracker);
+        loop {
+            while let Some(peer) = manager.next_attempt() {
+                let (seeder, manager) = (self.clone(), manager.clone());
+                tasks.spawn(async move {
+                    let res = async {
+                        let num_of_pieces = seeder.torrent.info.num_pieces();
+                        let mut conn = manager.connect(peer, seeder.torrent.info_hash(), reserved, num_of_pieces).await?;
+                        seeder.serve(&mut conn).await
+                    };
+                    (peer, res.await)
+                });
+            }
+            if tasks.is_empty() && more_peers.is_none() && incoming.is_none() {
+                return Ok(());
+            }
+            let retry_at = manager.next_retry();
+            tokio::select! {
+                Some(done) = tasks.join_next() => {
+                    // the slot of a task that panicked stays taken, its peer is never connected to again
+                    if let Ok((peer, res)) = done {
+                        manager.closed(peer, res.is_ok());
+                    }
+                }
+                _ = &mut rechoke => {}
+                _ = async {
+                    match retry_at {
+                        Some(retry_at) => tokio::time::sleep_until(retry_at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+                addrs = async {
+                    match more_peers.as_mut() {
+                        Some(more_peers) => more_peers.recv().await,
+                        None => std::future::pending().await,
+                    }
+                }, if more_peers.is_some() => match addrs {
+                    Some(addrs) => manager.add(addrs, PeerSource::Tracker),
+                    None => more_peers = None,
+                },
+                conn = async {
+                    match incoming.as_mut() {
+                        Some(incoming) => incoming.recv().await,
+                        None => std::future::pending().await,
+                    }
+                }, if incoming.is_some() => match conn {
+                    Some(mut conn) => {
+                        let Some(peer) = conn.peer_addr().filter(|peer| manager.accept(*peer)) else { continue };
+                        let seeder = self.clone();
+                        tasks.spawn(async move { (peer, seeder.serve(&mut conn).await) });
+                    }
+                    None => incoming = None,
+                },
+            }
+        }
+    }
+
+    /// Uploads to one peer until it leaves or we both have every piece
+    pub async fn serve(&self, conn: &mut PeerConnection) -> anyhow::Result<()> {
+        let peer = conn.peer_addr().context("Peer address")?;
+        conn.set_limits(self.limits.with_torrent(self.torrent_limit.clone()));
+        self.choker.join(peer);
+        let res = self.serve_peer(conn, peer).await;
+        self.choker.leave(&peer);
+        res
+    }
+
+    async fn serve_peer(&self, conn: &mut PeerConnection, peer: SocketAddr) -> anyhow::Result<()> {
+        // a peer assumes we have nothing when the bitfield is left out
+        if self.have.count() > 0 {
+            let bitfield = Message {
+                message_tag: MessageTag::Bitfield,
+                payload: Payload::SimplePayload(self.have.as_bytes().to_vec()),
+            };
+            conn.send(bitfield).await?;
+        }
+        loop {
+            if self.have.is_complete() && conn.have.is_complete() {
+                // two seeders have nothing to say to each other
+                return Ok(());
+            }
+            let changed = self.choker.changed();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            conn.set_choking(!self.choker.is_unchoked(&peer)).await?;
+            let message = match conn.requests.is_empty() {
+                true => tokio::select! {
+                    message = timeout(PEER_TIMEOUT, conn.recv()) => Some(message.context("Peer went quiet")??),
+                    _ = &mut changed => continue,
+                },
+                // whatever already arrived goes first, it may cancel the next block
+                false => conn.recv().now_or_never().transpose()?,
+            };
+            match message {
+                Some(message) => match message.message_tag {
+                    MessageTag::Interested => self.choker.interested(peer, true),
+                    MessageTag::NotInterested => self.choker.interested(peer, false),
+                    _ => {}
+                },
+                None => {
+                    let request = conn.requests.pop_front().expect("requests not empty");
+                    // requests for pieces we lack get no answer
+                    let piece = request.index as usize;
+                    if self.have.has(piece) && self.torrent.info.is_block(piece, request.begin, request.length) {
+                        send_block(&self.torrent, &self.storage, conn, request).await?;
+                        self.stats.uploaded(request.length as usize);
+                        self.choker.uploaded(&peer, request.length as usize);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers a request from `storage`, the caller checked it is a block of a piece we have
+pub async fn send_block(torrent: &Torrent, storage: &Storage, conn: &mut PeerConnection, request: RequestPayload) -> anyhow::Result<()> {
+    let offset = request.index as usize * torrent.info.pieces_length + request.begin as usize;
+    let block = storage.read_at(offset, request.length as usize)?;
+    let piece = ReceivePayload {
+        index: request.index,
+        begin: request.begin,
+        block,
+    };
+    conn.send(Message {
+        message_tag: MessageTag::Piece,
+        payload: Payload::SimplePayload(piece.to_vec()),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::MessageFramer,
+        requestwindow::{RequestWindow, BLOCK_SIZE},
+        torrent::single_file_torrent,
+        utils::fetch_a_piece,
+    };
+    use futures_util::{sink::SinkExt, stream::StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn test_serves_requests_and_honours_cancel() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_length as u32).map(|i| (i % 23) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, piece_length));
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let storage = Arc::new(Storage::new(&tor.info, output.to_str().unwrap()).unwrap());
+        storage.preallocate().unwrap();
+        // only the first piece is on disk
+        storage.write_piece(0, &data[..piece_length]).unwrap();
+        let stats = Arc::new(TransferStats::new(0));
+        let seeder = Seeder::new(tor.clone(), storage, stats.clone());
+        assert_eq!(seeder.have().pieces().collect::<Vec<_>>(), vec![0]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let num_of_pieces = tor.info.num_pieces();
+        let seeder = Arc::new(seeder);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let seeder = seeder.clone();
+                tokio::spawn(async move {
+                    let mut conn = PeerConnection::new(Framed::new(socket, MessageFramer), num_of_pieces);
+                    let _ = seeder.serve(&mut conn).await;
+                });
+            }
+        });
+
+        let stream = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let mut conn = PeerConnection::new(stream, num_of_pieces);
+        conn.set_interested(true).await.unwrap();
+        conn.wait_for_unchoke().await.unwrap();
+        assert_eq!(conn.have.pieces().collect::<Vec<_>>(), vec![0]);
+        let piece = fetch_a_piece(&tor, &mut conn, 0, &mut RequestWindow::default()).await.expect("Fetch failed");
+        assert_eq!(piece, &data[..piece_length]);
+
+        // a request for a piece the seeder lacks and a cancelled one get no answer,
+        // they go out in a single write so the cancel is there before the block is read
+        let mut peer = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let message = |message_tag, payload: RequestPayload| Message {
+            message_tag,
+            payload: Payload::SimplePayload(payload.to_vec()),
+        };
+        let block = |index, begin, length| RequestPayload { index, begin, length };
+        peer.send(Message { message_tag: MessageTag::Interested, payload: Payload::SimplePayload(Vec::new()) }).await.unwrap();
+        while peer.next().await.unwrap().unwrap().message_tag != MessageTag::Unchoke {}
+        peer.feed(message(MessageTag::Request, block(1, 0, BLOCK_SIZE as u32))).await.unwrap();
+        peer.feed(message(MessageTag::Request, block(0, 0, BLOCK_SIZE as u32))).await.unwrap();
+        peer.feed(message(MessageTag::Cancel, block(0, 0, BLOCK_SIZE as u32))).await.unwrap();
+        peer.feed(message(MessageTag::Request, block(0, BLOCK_SIZE as u32, 10))).await.unwrap();
+        peer.flush().await.unwrap();
+        let message = peer.next().await.unwrap().unwrap();
+        assert_eq!(message.message_tag, MessageTag::Piece);
+        let Payload::SimplePayload(mut payload) = message.payload else { panic!("Expected a piece") };
+        let received = ReceivePayload::new(&mut payload);
+        assert_eq!((received.index, received.begin), (0, BLOCK_SIZE as u32));
+        assert_eq!(received.block, &data[BLOCK_SIZE..BLOCK_SIZE + 10]);
+        assert_eq!(stats.uploaded.load(std::sync::atomic::Ordering::Relaxed), piece_length + 10);
+    }
+
+    #[tokio::test]
+    async fn test_reannounced_peer_connected_once() {
+        let tor = Arc::new(single_file_torrent(&[0; 4], 4));
+        let storage = Arc::new(Storage::new(&tor.info, "unused").unwrap());
+        let seeder = Seeder::with_pieces(tor, storage, Bitfield::new(1), Arc::new(TransferStats::default()));
+
+        // a peer that handshakes and then keeps quiet, every connection is counted
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, mut connections) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let connected = connected.clone();
+                tokio::spawn(async move {
+                    let mut handshake = [0u8; 68];
+                    socket.read_exact(&mut handshake).await.unwrap();
+                    socket.write_all(&handshake).await.unwrap();
+                    connected.send(()).unwrap();
+                    let _ = socket.read(&mut [0; 1024]).await;
+                });
+            }
+        });
+
+        // listed twice by the tracker and announced again while connected
+        let (more, mut more_peers) = mpsc::unbounded_channel();
+        more.send(vec![addr]).unwrap();
+        let seed = Arc::new(seeder).seed(vec![addr, addr], Some(&mut more_peers), None, [0; 8]);
+        assert!(timeout(Duration::from_millis(500), seed).await.is_err(), "Seeding ended with the peer connected");
+        let mut count = 0;
+        while connections.try_recv().is_ok() {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+    }
+}
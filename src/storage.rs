@@ -0,0 +1,268 @@
+//! Torrent data on disk.
+//!
+//! The torrent is one contiguous byte range split over its files. Pieces and
+//! blocks are written to their place in that range as soon as they are known,
+//! so memory only ever holds the pieces being downloaded.
+//!
+//! Skipped files are never created. The parts of pieces that overlap a skipped
+//! file and a wanted one are kept in a side file, `<output>.parts`, one piece
+//! after the other.
+
+use crate::{
+    filepriority::FilePriorities,
+    torrent::{FileEntry, Info},
+};
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug)]
+pub struct Storage {
+    /// the file of a single file torrent, the root directory of a multi file one
+    output: PathBuf,
+    files: Vec<FileEntry>,
+    /// one per file, then the part file
+    paths: Vec<PathBuf>,
+    piece_length: usize,
+    total_length: usize,
+    skipped: Vec<bool>,
+    /// piece -> its place in the part file, for pieces overlapping skipped and wanted files
+    part_slots: HashMap<usize, usize>,
+    /// open files by index, opened on first use
+    handles: Mutex<HashMap<usize, Arc<File>>>,
+}
+
+impl Storage {
+    /// A single file torrent is stored at `output`, a multi file torrent
+    /// recreates its directory tree with `output` as the root directory.
+    pub fn new(info: &Info, output: &str) -> anyhow::Result<Self> {
+        let output = PathBuf::from(output);
+        let files = info.file_entries()?;
+        let mut paths: Vec<PathBuf> = files
+            .iter()
+            .map(|file| match info.is_multi_file() {
+                true => output.join(&file.path),
+                false => output.clone(),
+            })
+            .collect();
+        let mut part_path = output.as_os_str().to_owned();
+        part_path.push(".parts");
+        paths.push(PathBuf::from(part_path));
+        Ok(Self {
+            output,
+            skipped: vec![false; files.len()],
+            files,
+            paths,
+            piece_length: info.pieces_length,
+            total_length: info.total_length(),
+            part_slots: HashMap::new(),
+            handles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Skipped files are left alone, only the pieces they share with wanted files are stored
+    pub fn with_priorities(mut self, info: &Info, priorities: &FilePriorities) -> Self {
+        self.skipped = (0..self.files.len()).map(|file| priorities.is_skipped(file)).collect();
+        let boundary = (0..info.num_pieces()).filter(|piece| {
+            let slices = info.files_for_piece(&self.files, *piece);
+            let skipped = slices.iter().filter(|slice| self.skipped[slice.file_index]).count();
+            skipped > 0 && skipped < slices.len()
+        });
+        self.part_slots = boundary.enumerate().map(|(slot, piece)| (piece, slot)).collect();
+        self
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// Creates every file at its final size up front, the files stay sparse
+    /// where the file system allows it. Existing data is kept.
+    pub fn preallocate(&self) -> anyhow::Result<()> {
+        (0..self.files.len()).filter(|index| !self.skipped[*index]).try_for_each(|index| {
+            let handle = self.handle(index)?;
+            let length = self.files[index].length as u64;
+            if handle.metadata()?.len() < length {
+                handle
+                    .set_len(length)
+                    .with_context(|| format!("preallocate {}", self.paths[index].display()))?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn write_piece(&self, piece: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.write_at(piece * self.piece_length, data)
+    }
+
+    pub fn read_piece(&self, piece: usize) -> anyhow::Result<Vec<u8>> {
+        let offset = piece * self.piece_length;
+        let length = self.piece_length.min(self.total_length.saturating_sub(offset));
+        self.read_at(offset, length).with_context(|| format!("Read piece {piece}"))
+    }
+
+    /// True when the piece on disk matches `hash`
+    pub fn check_piece(&self, piece: usize, hash: &[u8; 20]) -> bool {
+        self.read_piece(piece).is_ok_and(|data| Sha1::digest(&data).as_slice() == hash)
+    }
+
+    /// Writes `data` at `offset` in the concatenation of all files
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        for (index, file_offset, range) in self.spans(offset, data.len())? {
+            let handle = self.handle(index)?;
+            write_all_at(&handle, &data[range], file_offset as u64)
+                .with_context(|| format!("write {}", self.paths[index].display()))?;
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes at `offset` in the concatenation of all files
+    pub fn read_at(&self, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        for (index, file_offset, range) in self.spans(offset, length)? {
+            let handle = self.handle(index)?;
+            read_exact_at(&handle, &mut data[range], file_offset as u64)
+                .with_context(|| format!("read {}", self.paths[index].display()))?;
+        }
+        Ok(data)
+    }
+
+    /// Files overlapping `offset..offset + length`, as `(file index, offset in the file, range of the buffer)`.
+    /// What falls in a skipped file comes from the part file, which has the index after the last file.
+    fn spans(&self, offset: usize, length: usize) -> anyhow::Result<Vec<(usize, usize, Range<usize>)>> {
+        let mut spans = Vec::new();
+        for (index, file) in self.files.iter().enumerate() {
+            let start = offset.max(file.offset);
+            let end = (offset + length).min(file.offset + file.length);
+            if start >= end {
+                continue;
+            }
+            if !self.skipped[index] {
+                spans.push((index, start - file.offset, start - offset..end - offset));
+                continue;
+            }
+            // piece by piece, each one has its own place in the part file
+            let mut start = start;
+            while start < end {
+                let piece = start / self.piece_length;
+                let piece_end = end.min((piece + 1) * self.piece_length);
+                let slot = self
+                    .part_slots
+                    .get(&piece)
+                    .with_context(|| format!("Piece {piece} only holds skipped files"))?;
+                let part_offset = slot * self.piece_length + start - piece * self.piece_length;
+                spans.push((self.files.len(), part_offset, start - offset..piece_end - offset));
+                start = piece_end;
+            }
+        }
+        Ok(spans)
+    }
+
+    fn handle(&self, index: usize) -> anyhow::Result<Arc<File>> {
+        let mut handles = self.handles.lock().expect("handles lock poisoned");
+        if let Some(handle) = handles.get(&index) {
+            return Ok(handle.clone());
+        }
+        let path = &self.paths[index];
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context("create directory")?;
+        }
+        let handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        let handle = Arc::new(handle);
+        handles.insert(index, handle.clone());
+        Ok(handle)
+    }
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let written = file.seek_write(buf, offset)?;
+        buf = &buf[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::multi_file_info;
+
+    #[test]
+    fn test_positional_writes_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let info = multi_file_info(["dir/a", "dir/b", "dir/c"]);
+
+        let storage = Storage::new(&info, root.to_str().unwrap()).expect("Valid paths");
+        storage.preallocate().unwrap();
+        assert_eq!(fs::metadata(root.join("dir/b")).unwrap().len(), 20);
+        // the last piece first, then one straddling the first two files
+        storage.write_piece(3, b"CCCCCCCC").unwrap();
+        storage.write_piece(0, b"aaaaabbb").unwrap();
+        assert_eq!(storage.read_piece(3).unwrap(), b"CCCCCCCC");
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"aaaaa");
+        assert_eq!(fs::read(root.join("dir/c")).unwrap(), b"CCCCCCC");
+        assert_eq!(&fs::read(root.join("dir/b")).unwrap()[..3], b"bbb");
+    }
+
+    #[test]
+    fn test_skipped_file_never_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let info = multi_file_info(["dir/a", "dir/b", "dir/c"]);
+
+        let priorities = FilePriorities::select(3, "0,2").unwrap();
+        let storage = Storage::new(&info, root.to_str().unwrap()).expect("Valid paths").with_priorities(&info, &priorities);
+        storage.preallocate().unwrap();
+        // the first and the last piece hold a bit of the skipped file
+        storage.write_piece(0, b"aaaaabbb").unwrap();
+        storage.write_piece(3, b"bCCCCCCC").unwrap();
+        assert_eq!(storage.read_piece(0).unwrap(), b"aaaaabbb");
+        assert_eq!(storage.read_piece(3).unwrap(), b"bCCCCCCC");
+        assert!(storage.read_piece(1).is_err());
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"aaaaa");
+        assert_eq!(fs::read(root.join("dir/c")).unwrap(), b"CCCCCCC");
+        assert!(!root.join("dir/b").exists());
+        assert_eq!(fs::metadata(root.with_extension("parts")).unwrap().len(), 8 + 1);
+    }
+}
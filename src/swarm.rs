@@ -0,0 +1,1085 @@
+//! Downloading a torrent from many peers at once.
+//!
+//! Peers get the pieces verified so far in return: the `Choker` unchokes the ones
+//! we download the most from, their requests are answered between our own pieces.
+
+use crate::{
+    bitfield::Bitfield,
+    choker::Choker,
+    connectionmanager::{ConnectionManager, ConnectionSlots, PeerSource},
+    events::{DownloadEvent, Events},
+    message::{Message, MessageTag, Payload},
+    peerconnection::PeerConnection,
+    ratelimit::{Limits, RateLimit},
+    piecepicker::{PiecePicker, RarestFirst},
+    resume::Resume,
+    requestwindow::RequestWindow,
+    seed::send_block,
+    torrent::Torrent,
+    torrentreader::ReadState,
+    tracker::TransferStats,
+    endgame::SharedBlocks,
+    utils::{fetch_shared_piece, Choked, HashMismatch, Prefetch},
+};
+use anyhow::Context;
+use futures_util::FutureExt;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinSet,
+    time::timeout,
+};
+
+/// Peers connected at the same time unless configured otherwise
+pub const MAX_PEERS: usize = 30;
+/// A peer that sends nothing for this long is dropped
+const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+/// Pieces failing the hash check a peer may contribute to before it is banned
+pub const MAX_STRIKES: u32 = 3;
+
+/// Pieces still to be downloaded, shared by every peer task.
+///
+/// Once nothing is pending, idle peers are handed pieces other peers are already
+/// downloading (endgame), the blocks of those pieces are exchanged through `blocks`.
+pub struct PieceQueue {
+    state: Mutex<QueueState>,
+    /// woken whenever a piece is released or completed
+    changed: Notify,
+    pub blocks: SharedBlocks,
+}
+
+struct QueueState {
+    pending: BTreeSet<usize>,
+    /// pieces readers wait for, handed out before any other pending piece
+    reads: BTreeSet<usize>,
+    /// pieces handed out next, e.g. of high priority files
+    high: BTreeSet<usize>,
+    /// piece -> number of peers downloading it
+    in_flight: HashMap<usize, usize>,
+    picker: Box<dyn PiecePicker>,
+    /// peer -> pieces it contributed to that failed the hash check
+    strikes: HashMap<SocketAddr, u32>,
+    /// peers that sent bad data too often, they are disconnected and never used again
+    banned: HashSet<SocketAddr>,
+}
+
+impl PieceQueue {
+    /// Queue handing out pieces rarest first
+    pub fn new(pieces: impl IntoIterator<Item = usize>) -> Self {
+        Self::with_picker(pieces, Box::new(RarestFirst::default()))
+    }
+
+    pub fn with_picker(pieces: impl IntoIterator<Item = usize>, picker: Box<dyn PiecePicker>) -> Self {
+        Self::with_blocks(pieces, picker, SharedBlocks::default())
+    }
+
+    fn with_blocks(pieces: impl IntoIterator<Item = usize>, picker: Box<dyn PiecePicker>, blocks: SharedBlocks) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                pending: pieces.into_iter().collect(),
+                reads: BTreeSet::new(),
+                high: BTreeSet::new(),
+                in_flight: HashMap::new(),
+                picker,
+                strikes: HashMap::new(),
+                banned: HashSet::new(),
+            }),
+            changed: Notify::new(),
+            blocks,
+        }
+    }
+
+    /// Hands out the pending piece the picker chooses among those the peer has,
+    /// pieces readers wait for first, then high priority pieces.
+    /// In endgame it is the piece the peer has with the fewest peers downloading it.
+    pub fn claim(&self, have: &Bitfield) -> Option<usize> {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        let QueueState { pending, reads, high, picker, .. } = &mut *state;
+        let read: BTreeSet<usize> = reads.intersection(pending).copied().collect();
+        let urgent: BTreeSet<usize> = high.intersection(pending).copied().collect();
+        let picked = picker
+            .pick(have, &read)
+            .or_else(|| picker.pick(have, &urgent))
+            .or_else(|| picker.pick(have, pending));
+        if let Some(piece) = picked {
+            state.pending.remove(&piece);
+            state.in_flight.insert(piece, 1);
+            self.blocks.start(piece);
+            return Some(piece);
+        }
+        if !state.pending.is_empty() {
+            return None;
+        }
+        let (&piece, peers) = state
+            .in_flight
+            .iter_mut()
+            .filter(|(piece, _)| have.has(**piece))
+            .min_by_key(|(_, peers)| **peers)?;
+        *peers += 1;
+        Some(piece)
+    }
+
+    /// A peer stopped downloading the piece, once nobody is left on it
+    /// the piece is pending again and its blocks are dropped
+    pub fn release(&self, piece: usize) {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        if let Some(peers) = state.in_flight.get_mut(&piece) {
+            *peers -= 1;
+            if *peers == 0 {
+                state.in_flight.remove(&piece);
+                state.pending.insert(piece);
+                self.blocks.discard(piece);
+            }
+        }
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// The piece was verified, peers still downloading it in endgame cancel their requests
+    pub fn complete(&self, piece: usize) {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        state.in_flight.remove(&piece);
+        state.reads.remove(&piece);
+        state.high.remove(&piece);
+        state.picker.piece_completed(piece);
+        self.blocks.finish(piece);
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// The piece failed the hash check: its blocks are thrown away and it is pending again.
+    /// Every peer that delivered blocks for it gets a strike, at `MAX_STRIKES` it is banned.
+    pub fn fail(&self, piece: usize) {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        for peer in self.blocks.sources(piece) {
+            let strikes = state.strikes.entry(peer).or_default();
+            *strikes += 1;
+            if *strikes >= MAX_STRIKES {
+                state.banned.insert(peer);
+            }
+        }
+        // peers still on it in endgame see the piece is gone and give up on it
+        state.in_flight.remove(&piece);
+        state.pending.insert(piece);
+        self.blocks.discard(piece);
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// The pieces are handed out before every other pending piece but those readers wait for
+    pub fn prioritize(&self, pieces: impl IntoIterator<Item = usize>) {
+        self.state.lock().expect("queue lock poisoned").high.extend(pieces);
+    }
+
+    /// Readers wait for the pieces, they are handed out before every other pending piece
+    pub fn prioritize_reads(&self, pieces: impl IntoIterator<Item = usize>) {
+        self.state.lock().expect("queue lock poisoned").reads.extend(pieces);
+    }
+
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.state.lock().expect("queue lock poisoned").banned.contains(peer)
+    }
+
+    /// A peer announced its pieces with a bitfield
+    pub fn peer_bitfield(&self, bitfield: &Bitfield) {
+        self.state.lock().expect("queue lock poisoned").picker.peer_bitfield(bitfield);
+    }
+
+    /// A peer announced `pieces` with haves
+    pub fn peer_have(&self, pieces: &[usize]) {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        for &piece in pieces {
+            state.picker.peer_have(piece);
+        }
+    }
+
+    pub fn peer_gone(&self, bitfield: &Bitfield) {
+        self.state.lock().expect("queue lock poisoned").picker.peer_gone(bitfield);
+    }
+
+    /// Nothing left to hand out and nothing being downloaded
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.lock().expect("queue lock poisoned");
+        state.pending.is_empty() && state.in_flight.is_empty()
+    }
+}
+
+/// Downloads pieces of one torrent from every peer it is given
+pub struct Swarm {
+    torrent: Arc<Torrent>,
+    stats: Arc<TransferStats>,
+    reserved: [u8; 8],
+    picker: Box<dyn PiecePicker>,
+    /// pieces downloaded before the others, e.g. of high priority files
+    high: Vec<usize>,
+    /// peers that connected to us, see `Listener`
+    incoming: Option<mpsc::UnboundedReceiver<PeerConnection>>,
+    events: Events,
+    limits: Limits,
+    /// applies to this torrent only, on top of `limits`
+    torrent_limit: Arc<RateLimit>,
+    slots: ConnectionSlots,
+    /// pieces `TorrentReader`s wait for
+    reads: Option<Arc<ReadState>>,
+    choker: Arc<Choker>,
+    /// upper bound on simultaneous peer connections
+    pub max_peers: usize,
+}
+
+impl Swarm {
+    pub fn new(torrent: Arc<Torrent>, stats: Arc<TransferStats>, reserved: [u8; 8]) -> Self {
+        Self {
+            torrent,
+            stats,
+            reserved,
+            picker: Box::new(RarestFirst::default()),
+            high: Vec::new(),
+            incoming: None,
+            events: Events::default(),
+            limits: Limits::default(),
+            torrent_limit: Arc::new(RateLimit::default()),
+            slots: ConnectionSlots::default(),
+            reads: None,
+            choker: Arc::new(Choker::default()),
+            max_peers: MAX_PEERS,
+        }
+    }
+
+    /// Reports peers, verified and failed pieces and completion to `events`
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Every connection, outgoing and incoming, counts towards `limits`
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The limit of this torrent alone, unlimited until it is set. Take it before the
+    /// download starts to change it while the download runs.
+    pub fn torrent_limit(&self) -> Arc<RateLimit> {
+        self.torrent_limit.clone()
+    }
+
+    /// Connections count towards `slots` shared with other torrents, on top of `max_peers`
+    pub fn with_slots(mut self, slots: ConnectionSlots) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Peers that connected to us join the download as well
+    pub fn with_incoming(mut self, incoming: mpsc::UnboundedReceiver<PeerConnection>) -> Self {
+        self.incoming = Some(incoming);
+        self
+    }
+
+    /// The pieces are downloaded before the other wanted pieces but after those `TorrentReader`s
+    /// wait for, see `FilePriorities::high_pieces`
+    pub fn with_high_priority(mut self, pieces: impl IntoIterator<Item = usize>) -> Self {
+        self.high.extend(pieces);
+        self
+    }
+
+    /// Pieces `TorrentReader`s read are downloaded first and reported to them once verified
+    pub fn with_reads(mut self, reads: Arc<ReadState>) -> Self {
+        self.reads = Some(reads);
+        self
+    }
+
+    /// Replaces the default upload slots
+    pub fn with_choker(mut self, choker: Choker) -> Self {
+        self.choker = Arc::new(choker);
+        self
+    }
+
+    /// Replaces the default rarest first piece selection
+    pub fn with_picker(mut self, picker: impl PiecePicker + 'static) -> Self {
+        self.picker = Box::new(picker);
+        self
+    }
+
+    /// Downloads the `wanted` pieces into the storage behind `resume`. Pieces an earlier
+    /// run verified are skipped, blocks go to disk as they arrive so only the pieces
+    /// being downloaded are held in memory. Verified pieces are uploaded from there.
+    /// Peers arriving on `more_peers` (e.g. from tracker re-announces) join the download,
+    /// without it or incoming peers the download fails once every peer failed too often
+    /// and was forgotten, see `ConnectionManager`.
+    pub async fn download(
+        self,
+        resume: Arc<Resume>,
+        wanted: &[usize],
+        peers: Vec<SocketAddr>,
+        more_peers: Option<&mut mpsc::UnboundedReceiver<Vec<SocketAddr>>>,
+    ) -> anyhow::Result<()> {
+        let verified = resume.verified();
+        let missing: Vec<usize> = wanted.iter().copied().filter(|piece| !verified.has(*piece)).collect();
+        let blocks = SharedBlocks::with_resume(resume.clone());
+        let uploads = Some(resume.clone());
+        self.run(&missing, blocks, uploads, peers, more_peers, |piece, data| {
+            let resume = resume.clone();
+            async move { resume.piece_verified(piece, data).await }
+        })
+        .await
+    }
+
+    /// Same as download, but the pieces are kept in memory and returned concatenated in index order.
+    /// Nothing is uploaded.
+    pub async fn download_to_memory(
+        self,
+        wanted: &[usize],
+        peers: Vec<SocketAddr>,
+        more_peers: Option<&mut mpsc::UnboundedReceiver<Vec<SocketAddr>>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut pieces = BTreeMap::new();
+        self.run(wanted, SharedBlocks::default(), None, peers, more_peers, |piece, data| {
+            pieces.insert(piece, data);
+            std::future::ready(Ok(()))
+        })
+        .await?;
+        Ok(pieces.into_values().flatten().collect())
+    }
+
+    async fn run<F: Future<Output = anyhow::Result<()>>>(
+        mut self,
+        wanted: &[usize],
+        blocks: SharedBlocks,
+        uploads: Option<Arc<Resume>>,
+        peers: Vec<SocketAddr>,
+        mut more_peers: Option<&mut mpsc::UnboundedReceiver<Vec<SocketAddr>>>,
+        mut piece_done: impl FnMut(usize, Vec<u8>) -> F,
+    ) -> anyhow::Result<()> {
+        let num_of_pieces = self.torrent.info.num_pieces();
+        if let Some(piece) = wanted.iter().find(|piece| **piece >= num_of_pieces) {
+            anyhow::bail!("No piece {piece}, the torrent has {num_of_pieces}");
+        }
+        let picker = std::mem::replace(&mut self.picker, Box::new(RarestFirst::default()));
+        let queue = Arc::new(PieceQueue::with_blocks(wanted.iter().copied(), picker, blocks));
+        queue.prioritize(self.high.drain(..));
+        let mut remaining: BTreeSet<usize> = wanted.iter().copied().collect();
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(usize, Vec<u8>)>();
+        let manager = Arc::new(ConnectionManager::new(self.slots.clone(), self.max_peers));
+        manager.add(peers, PeerSource::Tracker);
+        let limits = self.limits.with_torrent(self.torrent_limit.clone());
+        let shared = Arc::new(Shared {
+            torrent: self.torrent.clone(),
+            queue: queue.clone(),
+            sender,
+            events: self.events.clone(),
+            manager: manager.clone(),
+            choker: self.choker.clone(),
+            stats: self.stats.clone(),
+            uploads,
+        });
+        // ranked by what they give us, we are not seeding yet
+        let choker = self.choker.clone();
+        let rechoke = async move { choker.run(|| false).await };
+        tokio::pin!(rechoke);
+        let mut tasks = JoinSet::new();
+        // once `piece_done` stored it, the piece counts as verified
+        let verified = |piece: usize, bytes: usize| {
+            self.stats.piece_verified(bytes);
+            self.events.send(DownloadEvent::PieceVerified { piece, bytes });
+            if let Some(reads) = &self.reads {
+                reads.piece_verified(piece);
+            }
+        };
+
+        while !remaining.is_empty() {
+            while let Some(peer) = manager.next_attempt() {
+                let (info_hash, reserved, num_of_pieces) = (self.torrent.info_hash(), self.reserved, self.torrent.info.num_pieces());
+                let (connecting, limits) = (manager.clone(), limits.clone());
+                let connect = async move {
+                    let mut conn = connecting.connect(peer, info_hash, reserved, num_of_pieces).await?;
+                    conn.set_limits(limits);
+                    Ok(conn)
+                };
+                tasks.spawn(peer_task(shared.clone(), connect));
+            }
+            // peers that failed are waited for until they are forgotten
+            if tasks.is_empty() && more_peers.is_none() && self.incoming.is_none() && !manager.has_candidates() {
+                // a peer may have finished a piece just before leaving
+                while let Ok((piece, data)) = receiver.try_recv() {
+                    if remaining.remove(&piece) {
+                        let bytes = data.len();
+                        piece_done(piece, data).await?;
+                        verified(piece, bytes);
+                    }
+                }
+                anyhow::ensure!(
+                    remaining.is_empty(),
+                    "Ran out of peers with {} pieces left",
+                    remaining.len()
+                );
+                break;
+            }
+            let retry_at = manager.next_retry();
+            tokio::select! {
+                // two peers can finish the same piece in endgame, only the first one counts
+                Some((piece, data)) = receiver.recv() => if remaining.remove(&piece) {
+                    let bytes = data.len();
+                    piece_done(piece, data).await?;
+                    verified(piece, bytes);
+                },
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                _ = &mut rechoke => {}
+                // PEX from a connected peer
+                _ = manager.added() => {}
+                _ = async {
+                    match &self.reads {
+                        Some(reads) => reads.requested().await,
+                        None => std::future::pending().await,
+                    }
+                } => queue.prioritize_reads(self.reads.iter().flat_map(|reads| reads.take_requested())),
+                _ = async {
+                    match retry_at {
+                        Some(retry_at) => tokio::time::sleep_until(retry_at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+                addrs = async {
+                    match more_peers.as_mut() {
+                        Some(more_peers) => more_peers.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => match addrs {
+                    Some(addrs) => manager.add(addrs, PeerSource::Tracker),
+                    None => more_peers = None,
+                },
+                conn = async {
+                    match self.incoming.as_mut() {
+                        Some(incoming) => incoming.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => match conn {
+                    Some(mut conn) => {
+                        conn.set_limits(limits.clone());
+                        let allowed = conn.peer_addr().is_some_and(|peer| !queue.is_banned(&peer) && manager.accept(peer));
+                        if allowed {
+                            let connect = std::future::ready(Ok(conn));
+                            tasks.spawn(peer_task(shared.clone(), connect));
+                        }
+                    }
+                    None => self.incoming = None,
+                },
+            }
+        }
+        tasks.abort_all();
+        self.events.send(DownloadEvent::Completed);
+        Ok(())
+    }
+}
+
+/// What the peer tasks of one download share
+struct Shared {
+    torrent: Arc<Torrent>,
+    queue: Arc<PieceQueue>,
+    sender: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+    events: Events,
+    manager: Arc<ConnectionManager>,
+    choker: Arc<Choker>,
+    stats: Arc<TransferStats>,
+    /// where verified pieces are uploaded from, nothing is uploaded without it
+    uploads: Option<Arc<Resume>>,
+}
+
+/// Downloads from the peer `connect` resolves to, an outgoing or an incoming connection
+async fn peer_task(shared: Arc<Shared>, connect: impl Future<Output = anyhow::Result<PeerConnection>>) -> anyhow::Result<()> {
+    let mut conn = connect.await?;
+    let peer = conn.peer_addr().context("Peer address")?;
+    shared.events.send(DownloadEvent::PeerConnected(peer));
+    shared.choker.join(peer);
+    let mut reported = Bitfield::new(shared.torrent.info.num_pieces());
+    let mut ahead = None;
+    let res = download_from_peer(&shared, &mut conn, &mut reported, &mut ahead).await;
+    // whatever the peer had no longer counts towards availability
+    shared.queue.peer_gone(&reported);
+    if let Some(piece) = ahead {
+        shared.queue.release(piece);
+    }
+    shared.choker.leave(&peer);
+    shared.events.send(DownloadEvent::PeerDisconnected(peer));
+    match shared.queue.is_banned(&peer) {
+        true => shared.manager.forget(peer),
+        false => {
+            // only now, while connected another connection would reach the same peer again
+            shared.manager.add(conn.listen_addrs.drain(..), PeerSource::Handshake);
+            shared.manager.closed(peer, res.is_ok());
+        }
+    }
+    res
+}
+
+/// Downloads pieces from one peer until the queue runs dry or the peer goes away.
+/// A piece the peer fails to deliver goes back into the queue.
+/// `reported` is what the queue was last told the peer has, `ahead` the piece claimed to be
+/// requested while the current one finishes.
+async fn download_from_peer(
+    shared: &Shared,
+    conn: &mut PeerConnection,
+    reported: &mut Bitfield,
+    ahead: &mut Option<usize>,
+) -> anyhow::Result<()> {
+    let Shared { torrent, queue, sender, events, manager, choker, .. } = shared;
+    let peer = conn.peer_addr().context("Peer address")?;
+    conn.send_extension_handshake().await?;
+    // what the peer was told we have
+    let mut announced = Bitfield::new(torrent.info.num_pieces());
+    if let Some(resume) = &shared.uploads {
+        announced = resume.verified();
+        // a peer assumes we have nothing when the bitfield is left out
+        if announced.count() > 0 {
+            let bitfield = Payload::SimplePayload(announced.as_bytes().to_vec());
+            conn.send(Message { message_tag: MessageTag::Bitfield, payload: bitfield }).await?;
+        }
+    }
+    conn.set_interested(true).await?;
+
+    let mut window = RequestWindow::default();
+    let mut prefetch = Prefetch::default();
+    loop {
+        if let Some(bitfield) = conn.new_bitfield.take() {
+            queue.peer_bitfield(&bitfield);
+            for piece in bitfield.pieces() {
+                reported.set(piece);
+            }
+        }
+        if !conn.new_pieces.is_empty() {
+            // haves arrived since the last time
+            queue.peer_have(&conn.new_pieces);
+            for piece in conn.new_pieces.drain(..) {
+                reported.set(piece);
+            }
+        }
+        if !conn.pex_peers.is_empty() {
+            manager.add(conn.pex_peers.drain(..), PeerSource::Pex);
+        }
+        let changed = queue.changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        let rechoked = choker.changed();
+        tokio::pin!(rechoked);
+        rechoked.as_mut().enable();
+        if queue.is_finished() {
+            return Ok(());
+        }
+        anyhow::ensure!(!queue.is_banned(&peer), "Banned {peer} for sending bad data");
+        upload_to_peer(shared, conn, peer, &mut announced).await?;
+        if conn.peer_choking {
+            // others may get it while this peer keeps us choked
+            if let Some(piece) = ahead.take() {
+                queue.release(piece);
+            }
+        } else if let Some(piece) = ahead.take().or_else(|| queue.claim(&conn.have)) {
+            // the next piece is requested while the last blocks of this one arrive
+            *ahead = queue.claim(&conn.have);
+            if *ahead == Some(piece) {
+                // endgame handed out the same piece twice
+                queue.release(piece);
+                *ahead = None;
+            }
+            match fetch_shared_piece(torrent, conn, piece, &mut window, &queue.blocks, &mut prefetch, *ahead).await {
+                Ok(Some(data)) => {
+                    queue.complete(piece);
+                    let _ = sender.send((piece, data));
+                    continue;
+                }
+                // another peer was faster in endgame
+                Ok(None) => {
+                    queue.release(piece);
+                    continue;
+                }
+                // the piece is downloaded again, by whoever is not banned
+                Err(e) if e.downcast_ref::<HashMismatch>().is_some() => {
+                    events.send(DownloadEvent::HashFailed { piece });
+                    queue.fail(piece);
+                    continue;
+                }
+                // someone else may get it while this peer keeps us choked
+                Err(e) if e.downcast_ref::<Choked>().is_some() => {
+                    queue.release(piece);
+                    continue;
+                }
+                // a stalled peer is dropped so the piece does not wait for endgame, see `Stalled`
+                Err(e) => {
+                    queue.release(piece);
+                    return Err(e.context(format!("Piece {piece} from {peer}")));
+                }
+            }
+        }
+        // nothing to do until a piece is released, the peer tells us something new or it is rechoked
+        tokio::select! {
+            _ = &mut changed => {}
+            _ = &mut rechoked => {}
+            message = timeout(PEER_TIMEOUT, conn.recv()) => {
+                message.context("Peer went quiet")??;
+            }
+        }
+    }
+}
+
+/// Tells the peer about the pieces verified since the last call, chokes or unchokes it as
+/// the choker decided and answers the requests it queued meanwhile.
+/// `announced` is what the peer was last told we have.
+async fn upload_to_peer(shared: &Shared, conn: &mut PeerConnection, peer: SocketAddr, announced: &mut Bitfield) -> anyhow::Result<()> {
+    shared.choker.downloaded(&peer, std::mem::take(&mut conn.downloaded));
+    shared.choker.interested(peer, conn.peer_interested);
+    let Some(resume) = &shared.uploads else {
+        return Ok(());
+    };
+    let verified = resume.verified();
+    for piece in verified.pieces().filter(|piece| !announced.has(*piece)) {
+        let have = Payload::SimplePayload((piece as u32).to_be_bytes().to_vec());
+        conn.send(Message { message_tag: MessageTag::Have, payload: have }).await?;
+    }
+    *announced = verified;
+    conn.set_choking(!shared.choker.is_unchoked(&peer)).await?;
+    while let Some(request) = conn.requests.pop_front() {
+        // requests for pieces we lack get no answer
+        let piece = request.index as usize;
+        if announced.has(piece) && shared.torrent.info.is_block(piece, request.begin, request.length) {
+            send_block(&shared.torrent, resume.storage(), conn, request).await?;
+            shared.stats.uploaded(request.length as usize);
+            shared.choker.uploaded(&peer, request.length as usize);
+        }
+        // whatever already arrived goes first, it may cancel the next block
+        while let Some(message) = conn.recv().now_or_never() {
+            message?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constant,
+        extension::{
+            extensionpayload::{ExtensionPayload, ExtensionType},
+            extensionpex::PexMessage,
+        },
+        handshake::Handshake,
+        listener::Listener,
+        message::{requestpayload::RequestPayload, MessageFramer},
+        piecepicker::Sequential,
+        seed::Seeder,
+        storage::Storage,
+        torrent::single_file_torrent,
+    };
+    use futures_util::{sink::SinkExt, stream::StreamExt};
+    use tokio_util::codec::Framed;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    /// Accepts one leecher, announces every piece and unchokes it after `delay`
+    async fn accept_leecher(listener: TcpListener, info_hash: [u8; 20], num_of_pieces: usize, delay: Duration) -> Framed<tokio::net::TcpStream, MessageFramer> {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        let reply = Handshake {
+            protocol_length: 19,
+            protocol_name: *b"BitTorrent protocol",
+            reserved: [0; 8],
+            info_hash,
+            peer_id: [7; 20],
+        };
+        socket.write_all(&reply.as_bytes()).await.unwrap();
+        let mut peer = Framed::new(socket, MessageFramer);
+        let bitfield = Bitfield::full(num_of_pieces).as_bytes().to_vec();
+        peer.send(Message { message_tag: MessageTag::Bitfield, payload: Payload::SimplePayload(bitfield) }).await.unwrap();
+        tokio::time::sleep(delay).await;
+        peer.send(Message { message_tag: MessageTag::Unchoke, payload: Payload::SimplePayload(Vec::new()) }).await.unwrap();
+        peer
+    }
+
+    /// Peer with every piece of `data`, it hangs up after answering `serve` requests
+    async fn seeder(data: Vec<u8>, info_hash: [u8; 20], serve: usize) -> SocketAddr {
+        slow_seeder(data, info_hash, serve, Duration::ZERO).await
+    }
+
+    /// Seeder that only unchokes after `delay`
+    async fn slow_seeder(data: Vec<u8>, info_hash: [u8; 20], serve: usize, delay: Duration) -> SocketAddr {
+        spawn_seeder(data, info_hash, serve, delay, 0).await
+    }
+
+    /// Seeder that flips a byte in each of the first `corrupt` blocks it sends
+    async fn corrupt_seeder(data: Vec<u8>, info_hash: [u8; 20], corrupt: usize) -> SocketAddr {
+        spawn_seeder(data, info_hash, usize::MAX, Duration::ZERO, corrupt).await
+    }
+
+    async fn spawn_seeder(data: Vec<u8>, info_hash: [u8; 20], serve: usize, delay: Duration, corrupt: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut peer = accept_leecher(listener, info_hash, data.len().div_ceil(PIECE_LENGTH), delay).await;
+            let mut served = 0;
+            while let Some(Ok(message)) = peer.next().await {
+                let Payload::SimplePayload(payload) = message.payload else { continue };
+                if message.message_tag != MessageTag::Request {
+                    continue;
+                }
+                if served == serve {
+                    return;
+                }
+                served += 1;
+                let index = u32::from_be_bytes(payload[0..4].try_into().unwrap()) as usize;
+                let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                let start = index * PIECE_LENGTH + begin;
+                let mut reply = payload[0..8].to_vec();
+                reply.extend_from_slice(&data[start..start + length]);
+                if served <= corrupt {
+                    reply[8] ^= 0xff;
+                }
+                let message = Message { message_tag: MessageTag::Piece, payload: Payload::SimplePayload(reply) };
+                if peer.send(message).await.is_err() {
+                    return;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_piece_reassigned_when_peer_leaves() {
+        let data: Vec<u8> = (0..5 * PIECE_LENGTH as u32 - 100).map(|i| (i % 251) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let info_hash = tor.info_hash();
+        // the first peer leaves halfway through a piece
+        let flaky = seeder(data.clone(), info_hash, 3).await;
+        let steady = seeder(data.clone(), info_hash, usize::MAX).await;
+
+        let stats = Arc::new(TransferStats::new(data.len()));
+        let swarm = Swarm::new(tor.clone(), stats.clone(), [0; 8]);
+        let wanted: Vec<usize> = (0..tor.info.num_pieces()).collect();
+        let downloaded = swarm.download_to_memory(&wanted, vec![flaky, steady], None).await.expect("Download failed");
+        assert_eq!(downloaded, data);
+        assert_eq!(stats.left.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        // a piece past the end is refused instead of waiting for a peer that has it
+        let swarm = Swarm::new(tor.clone(), stats, [0; 8]);
+        assert!(swarm.download_to_memory(&[tor.info.num_pieces()], Vec::new(), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_to_disk_skips_verified_pieces() {
+        let data: Vec<u8> = (0..3 * PIECE_LENGTH as u32).map(|i| (i % 17) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let output = output.to_str().unwrap();
+        let storage = Arc::new(Storage::new(&tor.info, output).unwrap());
+        storage.preallocate().unwrap();
+        let resume = Arc::new(Resume::open(tor.clone(), storage).unwrap());
+        resume.piece_verified(1, data[PIECE_LENGTH..2 * PIECE_LENGTH].to_vec()).await.unwrap();
+
+        // four requests are exactly the two missing pieces
+        let peer = seeder(data.clone(), tor.info_hash(), 4).await;
+        let stats = Arc::new(TransferStats::new(2 * PIECE_LENGTH));
+        let swarm = Swarm::new(tor, stats, [0; 8]);
+        swarm.download(resume, &[0, 1, 2], vec![peer], None).await.expect("Download failed");
+        assert_eq!(std::fs::read(output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_uploads_verified_pieces_while_downloading() {
+        let data: Vec<u8> = (0..2 * PIECE_LENGTH as u32).map(|i| (i % 23) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let info_hash = tor.info_hash();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let output = output.to_str().unwrap();
+        let storage = Arc::new(Storage::new(&tor.info, output).unwrap());
+        storage.preallocate().unwrap();
+        let resume = Arc::new(Resume::open(tor.clone(), storage).unwrap());
+        resume.piece_verified(1, data[PIECE_LENGTH..].to_vec()).await.unwrap();
+
+        // a peer without pieces that wants the one we have
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let leecher = listener.local_addr().unwrap();
+        let expected = data[PIECE_LENGTH..PIECE_LENGTH + 100].to_vec();
+        let served = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            socket.read_exact(&mut handshake).await.unwrap();
+            socket.write_all(&handshake).await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            let message = peer.next().await.unwrap().unwrap();
+            assert_eq!(message.message_tag, MessageTag::Bitfield);
+            assert_eq!(message.payload, Payload::SimplePayload(vec![0b0100_0000]));
+            peer.send(Message { message_tag: MessageTag::Interested, payload: Payload::SimplePayload(Vec::new()) }).await.unwrap();
+            while peer.next().await.unwrap().unwrap().message_tag != MessageTag::Unchoke {}
+            let request = RequestPayload { index: 1, begin: 0, length: 100 };
+            peer.send(Message { message_tag: MessageTag::Request, payload: Payload::SimplePayload(request.to_vec()) }).await.unwrap();
+            loop {
+                let message = peer.next().await.unwrap().unwrap();
+                if let (MessageTag::Piece, Payload::SimplePayload(payload)) = (message.message_tag, message.payload) {
+                    assert_eq!(&payload[..8], &request.to_vec()[..8]);
+                    assert_eq!(payload[8..], expected);
+                    return;
+                }
+            }
+        });
+        // the seeder holds back until the leecher was served
+        let peer = slow_seeder(data.clone(), info_hash, usize::MAX, Duration::from_millis(500)).await;
+
+        let stats = Arc::new(TransferStats::new(PIECE_LENGTH));
+        let swarm = Swarm::new(tor, stats.clone(), [0; 8]);
+        swarm.download(resume, &[0, 1], vec![leecher, peer], None).await.expect("Download failed");
+        timeout(Duration::from_secs(5), served).await.expect("Leecher was not served").unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), data);
+        assert_eq!(stats.uploaded.load(std::sync::atomic::Ordering::Relaxed), 100);
+    }
+
+    #[tokio::test]
+    async fn test_endgame_cancels_stalled_requests() {
+        let data: Vec<u8> = (0..PIECE_LENGTH as u32).map(|i| (i % 13) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let info_hash = tor.info_hash();
+        // a peer that accepts requests and never answers them, it gets the only piece first
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled = listener.local_addr().unwrap();
+        let (cancelled, got_cancel) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut peer = accept_leecher(listener, info_hash, 1, Duration::ZERO).await;
+            while let Some(Ok(message)) = peer.next().await {
+                if message.message_tag == MessageTag::Cancel {
+                    let _ = cancelled.send(());
+                    break;
+                }
+            }
+        });
+        let steady = slow_seeder(data.clone(), info_hash, usize::MAX, Duration::from_millis(200)).await;
+
+        let swarm = Swarm::new(tor, Arc::new(TransferStats::new(data.len())), [0; 8]);
+        let downloaded = timeout(Duration::from_secs(5), swarm.download_to_memory(&[0], vec![stalled, steady], None))
+            .await
+            .expect("Stalled peer held up the download")
+            .expect("Download failed");
+        assert_eq!(downloaded, data);
+        timeout(Duration::from_secs(5), got_cancel).await.expect("No cancel sent").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_piece_downloaded_again() {
+        let data: Vec<u8> = (0..2 * PIECE_LENGTH as u32).map(|i| (i % 19) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        // the first piece it sends is bad, one strike does not get it banned
+        let peer = corrupt_seeder(data.clone(), tor.info_hash(), 1).await;
+        let events = Events::default();
+        let mut received = events.subscribe();
+        let swarm = Swarm::new(tor, Arc::new(TransferStats::new(data.len())), [0; 8]).with_events(events);
+        let downloaded = swarm.download_to_memory(&[0, 1], vec![peer], None).await.expect("Download failed");
+        assert_eq!(downloaded, data);
+
+        let mut reported = Vec::new();
+        while let Ok(event) = received.try_recv() {
+            reported.push(event);
+        }
+        assert_eq!(reported.first(), Some(&DownloadEvent::PeerConnected(peer)));
+        assert_eq!(reported.iter().filter(|event| matches!(event, DownloadEvent::HashFailed { .. })).count(), 1);
+        assert_eq!(reported.iter().filter(|event| matches!(event, DownloadEvent::PieceVerified { .. })).count(), 2);
+        assert_eq!(reported.last(), Some(&DownloadEvent::Completed));
+    }
+
+    #[tokio::test]
+    async fn test_reads_go_before_high_priority_pieces() {
+        let data: Vec<u8> = (0..5 * PIECE_LENGTH as u32).map(|i| (i % 29) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (info_hash, served) = (tor.info_hash(), data.clone());
+        // the pieces in the order they were first requested
+        let order = tokio::spawn(async move {
+            // the swarm has taken the read before it can request anything
+            let mut peer = accept_leecher(listener, info_hash, 5, Duration::from_millis(100)).await;
+            let mut order = Vec::new();
+            while let Some(Ok(message)) = peer.next().await {
+                let Payload::SimplePayload(payload) = message.payload else { continue };
+                if message.message_tag != MessageTag::Request {
+                    continue;
+                }
+                let index = u32::from_be_bytes(payload[0..4].try_into().unwrap()) as usize;
+                let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                if !order.contains(&index) {
+                    order.push(index);
+                }
+                let start = index * PIECE_LENGTH + begin;
+                let mut reply = payload[0..8].to_vec();
+                reply.extend_from_slice(&served[start..start + length]);
+                if peer.send(Message { message_tag: MessageTag::Piece, payload: Payload::SimplePayload(reply) }).await.is_err() {
+                    break;
+                }
+            }
+            order
+        });
+
+        let reads = Arc::new(ReadState::new(Bitfield::new(5)));
+        reads.request([3]);
+        let swarm = Swarm::new(tor, Arc::new(TransferStats::new(data.len())), [0; 8])
+            .with_picker(Sequential)
+            .with_high_priority([1, 4])
+            .with_reads(reads.clone());
+        let wanted: Vec<usize> = (0..5).collect();
+        let downloaded = swarm.download_to_memory(&wanted, vec![addr], None).await.expect("Download failed");
+        assert_eq!(downloaded, data);
+        assert!(reads.is_verified(3));
+        assert_eq!(order.await.unwrap(), vec![3, 1, 4, 0, 2]);
+    }
+
+    /// Picker that learns availability from bitfields alone
+    struct BitfieldPicker(Arc<std::sync::Mutex<Vec<usize>>>);
+
+    impl PiecePicker for BitfieldPicker {
+        fn peer_bitfield(&mut self, bitfield: &Bitfield) {
+            self.0.lock().unwrap().extend(bitfield.pieces());
+        }
+
+        fn pick(&mut self, peer: &Bitfield, pending: &BTreeSet<usize>) -> Option<usize> {
+            pending.iter().copied().find(|piece| peer.has(*piece))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_picker_sees_bitfields() {
+        let data: Vec<u8> = (0..3 * PIECE_LENGTH as u32).map(|i| (i % 31) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let peer = seeder(data.clone(), tor.info_hash(), usize::MAX).await;
+        let announced = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let swarm = Swarm::new(tor, Arc::new(TransferStats::new(data.len())), [0; 8]).with_picker(BitfieldPicker(announced.clone()));
+        let downloaded = swarm.download_to_memory(&[0, 1, 2], vec![peer], None).await.expect("Download failed");
+        assert_eq!(downloaded, data);
+        assert_eq!(*announced.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_incoming_peer() {
+        let data: Vec<u8> = (0..2 * PIECE_LENGTH as u32).map(|i| (i % 29) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), [9; 20]).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = listener.routes().register(tor.info_hash(), tor.info.num_pieces(), [0; 8]);
+        tokio::spawn(listener.run());
+
+        // a seeder that finds us rather than the other way round
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        std::fs::write(&path, &data).unwrap();
+        let storage = Arc::new(Storage::new(&tor.info, path.to_str().unwrap()).unwrap());
+        let seeder = Seeder::new(tor.clone(), storage, Arc::new(TransferStats::default()));
+        let info_hash = tor.info_hash();
+        tokio::spawn(async move {
+            let mut conn = PeerConnection::connect(info_hash, &addr, [0; 8], 2).await.unwrap();
+            let _ = seeder.serve(&mut conn).await;
+        });
+
+        let swarm = Swarm::new(tor, Arc::new(TransferStats::new(data.len())), [0; 8]).with_incoming(incoming);
+        let downloaded = timeout(Duration::from_secs(5), swarm.download_to_memory(&[0, 1], Vec::new(), None))
+            .await
+            .expect("Incoming peer was not used")
+            .expect("Download failed");
+        assert_eq!(downloaded, data);
+    }
+
+    #[tokio::test]
+    async fn test_peer_found_through_pex() {
+        let data: Vec<u8> = (0..2 * PIECE_LENGTH as u32).map(|i| (i % 13) as u8).collect();
+        let tor = Arc::new(single_file_torrent(&data, PIECE_LENGTH));
+        let seeder = seeder(data.clone(), tor.info_hash(), usize::MAX).await;
+
+        // a peer without pieces that only tells us about the seeder
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gossip = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            socket.read_exact(&mut handshake).await.unwrap();
+            // same reserved bits as ours, extension protocol included
+            socket.write_all(&handshake).await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            let message = peer.next().await.unwrap().unwrap();
+            let Payload::ExtendedPayload(ExtensionPayload { payload: ExtensionType::ExtensionHandshakeMessage(ours), .. }) = message.payload else {
+                panic!("Expected an extension handshake first");
+            };
+            let mut added = match seeder.ip() {
+                std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+                std::net::IpAddr::V6(_) => unreachable!("bound to 127.0.0.1"),
+            };
+            added.extend_from_slice(&seeder.port().to_be_bytes());
+            let pex = ExtensionPayload { extension_id: ours.m.ut_pex, payload: ExtensionType::PexMessage(PexMessage { added, ..Default::default() }) };
+            peer.send(Message { message_tag: MessageTag::Extension, payload: Payload::ExtendedPayload(pex) }).await.unwrap();
+            while peer.next().await.is_some() {}
+        });
+
+        let swarm = Swarm::new(tor, Arc::new(TransferStats::new(data.len())), constant::get_reserved());
+        let downloaded = timeout(Duration::from_secs(5), swarm.download_to_memory(&[0, 1], vec![gossip], None))
+            .await
+            .expect("Peer from PEX was not used")
+            .expect("Download failed");
+        assert_eq!(downloaded, data);
+    }
+
+    #[tokio::test]
+    async fn test_peer_banned_after_strikes() {
+        let queue = PieceQueue::new(0..4);
+        let bad: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let good: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let have = Bitfield::full(4);
+        for strike in 1..=MAX_STRIKES {
+            let piece = queue.claim(&have).unwrap();
+            queue.blocks.insert(piece, 0, &[0], Some(bad)).await;
+            queue.fail(piece);
+            assert_eq!(queue.is_banned(&bad), strike == MAX_STRIKES);
+            assert!(!queue.blocks.is_active(piece));
+        }
+        assert!(!queue.is_banned(&good));
+        // every failed piece is back in the queue
+        let pieces: BTreeSet<usize> = (0..4).filter_map(|_| queue.claim(&have)).collect();
+        assert_eq!(pieces, (0..4).collect());
+    }
+
+    #[test]
+    fn test_queue_follows_bitfield() {
+        let queue = PieceQueue::with_picker(0..4, Box::new(Sequential));
+        let mut have = Bitfield::new(4);
+        have.set(2);
+        have.set(3);
+        assert_eq!(queue.claim(&have), Some(2));
+        assert_eq!(queue.claim(&have), Some(3));
+        assert_eq!(queue.claim(&have), None);
+        queue.release(3);
+        assert_eq!(queue.claim(&have), Some(3));
+        queue.complete(2);
+        queue.complete(3);
+        assert!(!queue.is_finished());
+        assert_eq!(queue.claim(&Bitfield::full(4)), Some(0));
+
+        // high priority pieces go first whatever the picker prefers, also once released
+        let queue = PieceQueue::with_picker(0..4, Box::new(Sequential));
+        queue.prioritize([2]);
+        assert_eq!(queue.claim(&Bitfield::full(4)), Some(2));
+        queue.release(2);
+        assert_eq!(queue.claim(&Bitfield::full(4)), Some(2));
+        assert_eq!(queue.claim(&Bitfield::full(4)), Some(0));
+
+        // pieces readers wait for go before high priority ones
+        let queue = PieceQueue::with_picker(0..4, Box::new(Sequential));
+        queue.prioritize([1]);
+        queue.prioritize_reads([3]);
+        assert_eq!(queue.claim(&Bitfield::full(4)), Some(3));
+        assert_eq!(queue.claim(&Bitfield::full(4)), Some(1));
+    }
+}
@@ -1,51 +1,245 @@
+use std::{collections::BTreeMap, ops::Range, path::PathBuf};
+use anyhow::Context;
 use serde::{Serialize, Deserialize};
 pub use pieces::Pieces;
 use sha1::{Digest, Sha1};
+use crate::bencode::{self, BencodeValue};
+use crate::requestwindow::BLOCK_SIZE;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Torrent {
     /// The URL of the tracker.
+    #[serde(default)]
     pub announce: String,
+    /// BEP 12 tiers of tracker URLs, preferred over `announce` when present
+    #[serde(rename = "announce-list", default, skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: Info,
+    /// The info dictionary exactly as it appeared in the metainfo file.
+    /// None when the torrent was built in memory, e.g. from magnet metadata.
+    #[serde(skip)]
+    pub info_bytes: Option<Vec<u8>>,
 }
 
 impl Torrent{
+    pub fn new(announce: String, info: Info) -> Self {
+        Self { announce, announce_list: None, info, info_bytes: None }
+    }
+
+    /// Parses a metainfo file and keeps the byte span of its info dictionary
+    pub fn from_bytes(content: &[u8]) -> anyhow::Result<Torrent> {
+        let mut tor: Torrent = serde_bencode::from_bytes(content).context("Convert file to a struct")?;
+        let span = bencode::dict_value_span(content, b"info")
+            .context("Locating info dictionary")?
+            .context("Metainfo has no info dictionary")?;
+        tor.info_bytes = Some(content[span].to_vec());
+        tor.info.validate()?;
+        Ok(tor)
+    }
+
+    /// Bencodes the torrent, keys we don't model inside info are written back as they were read
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("Bencode torrent")
+    }
+
+    /// SHA-1 of the original info dictionary bytes, re-encoding is only the fallback
     pub fn info_hash(&self) -> [u8;20]{
-        let info_bencoded_bytes = serde_bencode::to_bytes(&self.info)
-            .expect("Info Bencode failed");
+        let info_bencoded_bytes = match &self.info_bytes {
+            Some(bytes) => bytes.clone(),
+            None => serde_bencode::to_bytes(&self.info).expect("Info Bencode failed"),
+        };
         let mut hasher = Sha1::new();
         hasher.update(info_bencoded_bytes);
-        hasher.finalize().try_into().expect("Type conversion failed")
+        hasher.finalize().into()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Info{
-    pub length: usize,
-    /// suggested name
+    /// single file mode: size of the file in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// multi file mode: every file in the torrent, in piece order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<File>>,
+    /// suggested name, the file name in single file mode and the root directory otherwise
     pub name: String,
     /// the # of bytes in each piece
     #[serde(rename = "piece length")]
     pub pieces_length: usize,
     // concatenated SHA-1 hashes of each piece
     pub pieces: Pieces,
+    /// keys we don't model (private, source, md5sum, ...) kept so the info hash stays intact
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, BencodeValue>,
 }
 
-impl PartialEq for Info {
-    fn eq(&self, other: &Self) -> bool {
-        // Compare only the fields you care about
-        todo!("Info Struct's partial eq needs work")
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct File{
+    pub length: usize,
+    /// path components relative to the torrent's root directory
+    pub path: Vec<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, BencodeValue>,
+}
+
+/// A file laid out in the torrent's byte stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry{
+    /// path relative to the download location
+    pub path: PathBuf,
+    pub length: usize,
+    /// offset of the file's first byte in the concatenation of all files
+    pub offset: usize,
+}
+
+/// The part of a file covered by a piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSlice{
+    pub file_index: usize,
+    /// where the slice starts inside the file
+    pub file_offset: usize,
+    /// where the slice starts inside the piece
+    pub piece_offset: usize,
+    pub length: usize,
+}
+
+impl Info{
+    /// Size of the whole torrent in bytes
+    pub fn total_length(&self) -> usize {
+        match (&self.length, &self.files) {
+            (Some(length), _) => *length,
+            (None, Some(files)) => files.iter().map(|file| file.length).sum(),
+            (None, None) => 0,
+        }
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.0.len()
+    }
+
+    /// Checks the piece geometry everything else relies on: a known, non-zero length
+    /// split into pieces of a non-zero length, with one hash per piece
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.length.is_some() || self.files.is_some(), "Info has neither length nor files");
+        anyhow::ensure!(self.pieces_length > 0, "Piece length is 0");
+        let total_length = self.total_length();
+        anyhow::ensure!(total_length > 0, "Torrent has no content");
+        let expected = total_length.div_ceil(self.pieces_length);
+        anyhow::ensure!(
+            self.num_pieces() == expected,
+            "{} piece hashes for {total_length} bytes in pieces of {}, expected {expected}",
+            self.num_pieces(),
+            self.pieces_length
+        );
+        Ok(())
+    }
+
+    /// Every piece is `piece length` long except the last one which gets the remainder
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        let num_of_pieces = self.num_pieces();
+        if piece_index < num_of_pieces - 1 {
+            self.pieces_length
+        } else {
+            self.total_length() - (self.pieces_length * (num_of_pieces - 1))
+        }
+    }
+
+    /// A block a peer may ask for: inside an existing piece, not empty and no larger than `BLOCK_SIZE`
+    pub fn is_block(&self, piece_index: usize, begin: u32, length: u32) -> bool {
+        piece_index < self.num_pieces()
+            && length > 0
+            && length as usize <= BLOCK_SIZE
+            && begin as usize + length as usize <= self.piece_size(piece_index)
+    }
+
+    /// Files in piece order. In single file mode the path is just the name,
+    /// in multi file mode paths are relative to the root directory.
+    /// Fails on paths that could escape the download location.
+    pub fn file_entries(&self) -> anyhow::Result<Vec<FileEntry>> {
+        let Some(files) = &self.files else {
+            return Ok(vec![FileEntry {
+                path: sanitized_path(std::slice::from_ref(&self.name))?,
+                length: self.total_length(),
+                offset: 0,
+            }]);
+        };
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let entry = FileEntry {
+                    path: sanitized_path(&file.path)?,
+                    length: file.length,
+                    offset,
+                };
+                offset += file.length;
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    /// Pieces overlapping file `file_index`
+    pub fn pieces_for_file(&self, files: &[FileEntry], file_index: usize) -> Range<usize> {
+        let file = &files[file_index];
+        let first = file.offset / self.pieces_length;
+        let last = (file.offset + file.length).div_ceil(self.pieces_length);
+        first..last.max(first)
+    }
+
+    /// Where the piece lies in the concatenation of all files
+    pub fn piece_range(&self, piece_index: usize) -> Range<usize> {
+        let piece_start = piece_index * self.pieces_length;
+        piece_start..piece_start + self.piece_size(piece_index)
+    }
+
+    /// The file slices a piece is made of, in order
+    pub fn files_for_piece(&self, files: &[FileEntry], piece_index: usize) -> Vec<FileSlice> {
+        let Range { start: piece_start, end: piece_end } = self.piece_range(piece_index);
+        files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < piece_end && file.offset + file.length > piece_start)
+            .map(|(file_index, file)| {
+                let start = piece_start.max(file.offset);
+                let end = piece_end.min(file.offset + file.length);
+                FileSlice {
+                    file_index,
+                    file_offset: start - file.offset,
+                    piece_offset: start - piece_start,
+                    length: end - start,
+                }
+            })
+            .collect()
     }
 }
 
-// Then implement Eq (marker trait)
-impl Eq for Info {}
+fn sanitized_path(components: &[String]) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(!components.is_empty(), "Empty file path");
+    let mut path = PathBuf::new();
+    for component in components {
+        anyhow::ensure!(
+            !component.is_empty()
+                && component != "."
+                && component != ".."
+                && !component.contains(['/', '\\', '\0']),
+            "Unsafe path component {:?}",
+            component
+        );
+        path.push(component);
+    }
+    Ok(path)
+}
 
 mod pieces{
     use serde::de::{ Deserialize};
     use serde::ser::{Serialize, Serializer};
 
-    #[derive(Debug)]
+    #[derive(Debug, PartialEq, Eq)]
     pub struct Pieces(pub Vec<[u8;20]>);
     struct IPieces;
 
@@ -60,8 +254,8 @@ mod pieces{
         fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
             where
                 E: serde::de::Error, {
-                    if &v.len() % 20 != 0{
-                        return Err(E::custom(format!("Not a multiple of 20")))
+                    if !v.len().is_multiple_of(20){
+                        return Err(E::custom("Not a multiple of 20".to_string()))
                     }
 
                     let pieces = 
@@ -101,3 +295,100 @@ mod pieces{
 
 
 
+
+/// Test fixture: files of 5, 20 and 7 bytes at `paths` under `root`, in pieces of 8 bytes.
+/// Paths are split at `/` into their components.
+#[cfg(test)]
+pub fn multi_file_info(paths: [&str; 3]) -> Info {
+    let mut content = b"d5:filesl".to_vec();
+    for (length, path) in [5, 20, 7].into_iter().zip(paths) {
+        let components: String = path.split('/').map(|component| format!("{}:{component}", component.len())).collect();
+        content.extend_from_slice(format!("d6:lengthi{length}e4:pathl{components}ee").as_bytes());
+    }
+    content.extend_from_slice(b"e4:name4:root12:piece lengthi8e6:pieces80:");
+    content.extend_from_slice(&[0u8; 80]);
+    content.push(b'e');
+    serde_bencode::from_bytes(&content).expect("Parsing failed")
+}
+
+/// Test fixture: a single file holding `data`, in pieces of `piece_length` bytes with their
+/// real hashes
+#[cfg(test)]
+pub fn single_file_torrent(data: &[u8], piece_length: usize) -> Torrent {
+    let hashes: Vec<u8> = data.chunks(piece_length).flat_map(|piece| Sha1::digest(piece).to_vec()).collect();
+    let mut content = format!(
+        "d6:lengthi{}e4:name4:file12:piece lengthi{piece_length}e6:pieces{}:",
+        data.len(),
+        hashes.len()
+    )
+    .into_bytes();
+    content.extend_from_slice(&hashes);
+    content.push(b'e');
+    Torrent::new(String::new(), serde_bencode::from_bytes(&content).expect("Parsing failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metainfo_with_unmodelled_keys() -> Vec<u8> {
+        let mut content = b"d8:announce9:http://t/4:infod6:lengthi5e6:md5sum4:abcd4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        content.extend_from_slice(&[0xAB; 20]);
+        content.extend_from_slice(b"7:privatei1e6:source3:xyz6:vendord1:kl1:v1:weeee");
+        content
+    }
+
+    #[test]
+    fn test_info_hash_uses_original_bytes() {
+        let content = metainfo_with_unmodelled_keys();
+        let tor = Torrent::from_bytes(&content).expect("Parsing failed");
+        let start = content.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let expected: [u8; 20] = Sha1::digest(&content[start..content.len() - 1]).into();
+        assert_eq!(tor.info_hash(), expected);
+        assert_eq!(tor.info.extra.get("private"), Some(&BencodeValue::Int(1)));
+    }
+
+    #[test]
+    fn test_unknown_info_keys_round_trip() {
+        let content = metainfo_with_unmodelled_keys();
+        let tor = Torrent::from_bytes(&content).expect("Parsing failed");
+        assert_eq!(tor.to_bytes().expect("Encoding failed"), content);
+
+        let info_hash = tor.info_hash();
+        let rebuilt = Torrent::new(tor.announce, tor.info);
+        assert_eq!(rebuilt.info_hash(), info_hash);
+    }
+
+    #[test]
+    fn test_multi_file_piece_mapping() {
+        let mut info = multi_file_info(["a", "b", "c"]);
+        assert!(info.validate().is_ok());
+        assert_eq!(info.total_length(), 32);
+        assert_eq!(info.piece_size(3), 8);
+        let files = info.file_entries().expect("Valid paths");
+        assert_eq!(files[2].offset, 25);
+        assert_eq!(info.pieces_for_file(&files, 1), 0..4);
+        assert_eq!(info.pieces_for_file(&files, 2), 3..4);
+        assert_eq!(
+            info.files_for_piece(&files, 0),
+            vec![
+                FileSlice { file_index: 0, file_offset: 0, piece_offset: 0, length: 5 },
+                FileSlice { file_index: 1, file_offset: 0, piece_offset: 5, length: 3 },
+            ]
+        );
+
+        // geometry that would make piece sizes underflow or divide by zero
+        info.pieces.0.pop();
+        assert!(info.validate().is_err());
+        info.pieces.0.clear();
+        assert!(info.validate().is_err());
+        info.pieces_length = 0;
+        assert!(info.validate().is_err());
+    }
+
+    #[test]
+    fn test_unsafe_paths_rejected() {
+        let info = multi_file_info(["a", "..", "c"]);
+        assert!(info.file_entries().is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Reading torrent content while it downloads.
+//!
+//! A `TorrentReader` reads the whole torrent, or one of its files, from storage
+//! as its pieces verify. Through the `ReadState` it shares with the `Swarm`, a
+//! read asks for the piece under the read position and the few after it to be
+//! downloaded before any other, then waits until that piece verified.
+
+use crate::{bitfield::Bitfield, storage::Storage, torrent::Torrent};
+use anyhow::Context as _;
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    io::{self, SeekFrom},
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    sync::{futures::Notified, Notify},
+};
+
+/// Pieces from the read position on that are downloaded before all others
+pub const READAHEAD: usize = 4;
+
+/// What readers share with a running download: the pieces verified so far and
+/// the pieces readers are waiting for
+#[derive(Debug)]
+pub struct ReadState {
+    state: Mutex<ReadStateInner>,
+    /// woken whenever a piece verified
+    verified_changed: Notify,
+    /// woken when readers asked for pieces
+    requested_changed: Notify,
+}
+
+#[derive(Debug)]
+struct ReadStateInner {
+    verified: Bitfield,
+    /// asked for since the download last looked
+    requested: BTreeSet<usize>,
+}
+
+impl ReadState {
+    /// `verified` are the pieces on disk already, e.g. `Resume::verified`
+    pub fn new(verified: Bitfield) -> Self {
+        Self {
+            state: Mutex::new(ReadStateInner {
+                verified,
+                requested: BTreeSet::new(),
+            }),
+            verified_changed: Notify::new(),
+            requested_changed: Notify::new(),
+        }
+    }
+
+    pub fn piece_verified(&self, piece: usize) {
+        self.state.lock().expect("read state lock poisoned").verified.set(piece);
+        self.verified_changed.notify_waiters();
+    }
+
+    pub fn is_verified(&self, piece: usize) -> bool {
+        self.state.lock().expect("read state lock poisoned").verified.has(piece)
+    }
+
+    /// Asks for the pieces to be downloaded first, verified ones are left out
+    pub fn request(&self, pieces: impl IntoIterator<Item = usize>) {
+        let mut state = self.state.lock().expect("read state lock poisoned");
+        let state = &mut *state;
+        let mut requested = false;
+        for piece in pieces.into_iter().filter(|piece| !state.verified.has(*piece)) {
+            requested |= state.requested.insert(piece);
+        }
+        if requested {
+            self.requested_changed.notify_one();
+        }
+    }
+
+    /// The pieces readers asked for since the last call
+    pub fn take_requested(&self) -> Vec<usize> {
+        let requested = std::mem::take(&mut self.state.lock().expect("read state lock poisoned").requested);
+        requested.into_iter().collect()
+    }
+
+    /// Resolves when readers asked for pieces since the last call
+    pub fn requested(&self) -> Notified<'_> {
+        self.requested_changed.notified()
+    }
+
+    pub async fn wait_verified(&self, piece: usize) {
+        loop {
+            let changed = self.verified_changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if self.is_verified(piece) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+type ReadFuture = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// `AsyncRead` and `AsyncSeek` over a torrent being downloaded into `storage`.
+/// Only pieces the download wants ever verify, reading a skipped file waits forever.
+pub struct TorrentReader {
+    torrent: Arc<Torrent>,
+    storage: Arc<Storage>,
+    reads: Arc<ReadState>,
+    /// what is read, in the concatenation of all files
+    range: Range<usize>,
+    readahead: usize,
+    /// from the start of `range`
+    position: u64,
+    /// data from `position` on, up to the end of its piece
+    buffer: Vec<u8>,
+    consumed: usize,
+    pending: Option<ReadFuture>,
+}
+
+impl TorrentReader {
+    /// Reads the whole torrent, its files one after the other
+    pub fn new(torrent: Arc<Torrent>, storage: Arc<Storage>, reads: Arc<ReadState>) -> Self {
+        let range = 0..torrent.info.total_length();
+        Self::with_range(torrent, storage, reads, range)
+    }
+
+    /// Reads file `file_index` of the torrent
+    pub fn file(torrent: Arc<Torrent>, storage: Arc<Storage>, reads: Arc<ReadState>, file_index: usize) -> anyhow::Result<Self> {
+        let files = torrent.info.file_entries()?;
+        let file = files.get(file_index).with_context(|| format!("No file {file_index} in the torrent"))?;
+        let range = file.offset..file.offset + file.length;
+        Ok(Self::with_range(torrent, storage, reads, range))
+    }
+
+    fn with_range(torrent: Arc<Torrent>, storage: Arc<Storage>, reads: Arc<ReadState>, range: Range<usize>) -> Self {
+        Self {
+            torrent,
+            storage,
+            reads,
+            range,
+            readahead: READAHEAD,
+            position: 0,
+            buffer: Vec::new(),
+            consumed: 0,
+            pending: None,
+        }
+    }
+
+    /// Replaces the number of pieces downloaded first, the one being read included
+    pub fn with_readahead(mut self, pieces: usize) -> Self {
+        self.readahead = pieces.max(1);
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.range.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    /// Waits for the piece under the read position and reads it from there on
+    fn read_piece(&self) -> ReadFuture {
+        let info = &self.torrent.info;
+        let offset = self.range.start + self.position as usize;
+        let piece = offset / info.pieces_length;
+        let end = info.piece_range(piece).end.min(self.range.end);
+        let last = (self.range.end - 1) / info.pieces_length;
+        let ahead = piece..(piece + self.readahead).min(last + 1);
+        let (reads, storage) = (self.reads.clone(), self.storage.clone());
+        Box::pin(async move {
+            reads.request(ahead);
+            reads.wait_verified(piece).await;
+            tokio::task::spawn_blocking(move || storage.read_at(offset, end - offset))
+                .await
+                .map_err(io::Error::other)?
+                .map_err(|e| io::Error::other(format!("{e:#}")))
+        })
+    }
+}
+
+impl AsyncRead for TorrentReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.consumed < this.buffer.len() {
+                let length = buf.remaining().min(this.buffer.len() - this.consumed);
+                buf.put_slice(&this.buffer[this.consumed..this.consumed + length]);
+                this.consumed += length;
+                this.position += length as u64;
+                return Poll::Ready(Ok(()));
+            }
+            if this.position >= this.len() {
+                return Poll::Ready(Ok(()));
+            }
+            if this.pending.is_none() {
+                this.pending = Some(this.read_piece());
+            }
+            let pending = this.pending.as_mut().expect("read in progress");
+            let data = ready!(pending.as_mut().poll(cx));
+            this.pending = None;
+            this.buffer = data?;
+            this.consumed = 0;
+        }
+    }
+}
+
+impl AsyncSeek for TorrentReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => this.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start"))?;
+        if position != this.position {
+            this.position = position;
+            this.buffer.clear();
+            this.consumed = 0;
+            this.pending = None;
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::multi_file_info;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_reads_wait_for_requested_pieces() {
+        // files of 5, 20 and 7 bytes in pieces of 8
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let info = multi_file_info(["a", "b", "c"]);
+        let torrent = Arc::new(Torrent::new(String::new(), info));
+        let data: Vec<u8> = (0..32).collect();
+        let storage = Arc::new(Storage::new(&torrent.info, root.to_str().unwrap()).unwrap());
+        storage.write_at(0, &data).unwrap();
+
+        // the download verifies exactly the pieces readers ask for, in the order asked
+        let reads = Arc::new(ReadState::new(Bitfield::new(4)));
+        let downloader = reads.clone();
+        let asked = tokio::spawn(async move {
+            let mut asked = Vec::new();
+            while asked.len() < 4 {
+                downloader.requested().await;
+                for piece in downloader.take_requested() {
+                    asked.push(piece);
+                    downloader.piece_verified(piece);
+                }
+            }
+            asked
+        });
+
+        let mut reader = TorrentReader::file(torrent.clone(), storage.clone(), reads.clone(), 1).unwrap().with_readahead(2);
+        assert_eq!(reader.len(), 20);
+        assert_eq!(reader.seek(SeekFrom::Start(10)).await.unwrap(), 10);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, &data[15..25]);
+        reader.seek(SeekFrom::End(-20)).await.unwrap();
+        let mut first = [0u8; 4];
+        reader.read_exact(&mut first).await.unwrap();
+        assert_eq!(first, data[5..9]);
+
+        // piece 1 with the one after it, the file's last piece, then its first
+        assert_eq!(asked.await.unwrap(), vec![1, 2, 3, 0]);
+        let mut whole = Vec::new();
+        TorrentReader::new(torrent, storage, reads).read_to_end(&mut whole).await.unwrap();
+        assert_eq!(whole, data);
+    }
+}
@@ -0,0 +1,492 @@
+//! Tracker tiers (BEP 12) and announcing to them
+
+use crate::{
+    events::{DownloadEvent, Events},
+    httprequest::{Event, Request, Response, TrackerError},
+    torrent::Torrent,
+    udptracker::{ScrapeStats, UdpTracker},
+};
+use anyhow::Context;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use urlencoding::encode_binary;
+
+/// Longest one tracker may take to answer before the next one is tried
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Shortest re-announce interval we accept, a tracker asking for 0 would have us announce nonstop
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Trackers grouped in tiers, each tier is shuffled once when the list is built
+#[derive(Debug, Default)]
+pub struct TrackerList {
+    pub tiers: Vec<Vec<String>>,
+    /// UDP clients are kept around so their connection IDs can be reused
+    udp_trackers: HashMap<String, UdpTracker>,
+    /// `tracker id` handed out by each tracker, sent back on later announces
+    tracker_ids: HashMap<String, String>,
+}
+
+impl TrackerList {
+    /// Uses `announce-list` when present and falls back to `announce`
+    pub fn from_torrent(tor: &Torrent) -> Self {
+        let tiers = match &tor.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers.clone(),
+            _ => vec![vec![tor.announce.clone()]],
+        };
+        Self::new(tiers)
+    }
+
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        let mut rng = rand::thread_rng();
+        tiers.iter_mut().for_each(|tier| tier.shuffle(&mut rng));
+        Self { tiers, ..Default::default() }
+    }
+
+    /// Moves a tracker that answered to the front of its tier
+    pub fn promote(&mut self, tier: usize, index: usize) {
+        let url = self.tiers[tier].remove(index);
+        self.tiers[tier].insert(0, url);
+    }
+
+    /// Announces to every tier, trying the trackers of a tier in order until one answers.
+    /// Peers from all tiers are merged, it only fails if no tracker answered at all.
+    pub async fn announce(&mut self, info_hash: &[u8; 20], request: &Request) -> anyhow::Result<Response> {
+        let mut merged: Option<Response> = None;
+        let mut last_error = None;
+        for tier in 0..self.tiers.len() {
+            for index in 0..self.tiers[tier].len() {
+                let url = self.tiers[tier][index].clone();
+                let response = tokio::time::timeout(TRACKER_TIMEOUT, self.announce_to(&url, info_hash, request)).await;
+                match response.unwrap_or_else(|_| Err(anyhow::anyhow!("{url} did not answer in time"))) {
+                    Ok(response) => {
+                        self.promote(tier, index);
+                        merged = Some(match merged {
+                            Some(merged) => merge(merged, response),
+                            None => response,
+                        });
+                        break;
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+        match (merged, last_error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e.context("No tracker answered")),
+            (None, None) => anyhow::bail!("Torrent has no trackers"),
+        }
+    }
+
+    /// Scrapes the first tracker that answers, tiers and trackers are tried in order
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+        let mut last_error = None;
+        for url in self.tiers.iter().flatten().cloned().collect::<Vec<_>>() {
+            let stats = tokio::time::timeout(TRACKER_TIMEOUT, self.scrape_from(&url, info_hashes)).await;
+            match stats.unwrap_or_else(|_| Err(anyhow::anyhow!("{url} did not answer in time"))) {
+                Ok(stats) => return Ok(stats),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.context("No tracker answered the scrape")),
+            None => anyhow::bail!("Torrent has no trackers"),
+        }
+    }
+
+    async fn scrape_from(&mut self, url: &str, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+        if !url.starts_with("udp://") {
+            return scrape(url, info_hashes).await;
+        }
+        if !self.udp_trackers.contains_key(url) {
+            let tracker = UdpTracker::new(url).await?;
+            self.udp_trackers.insert(url.to_string(), tracker);
+        }
+        let tracker = self.udp_trackers.get_mut(url).expect("inserted above");
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(UDP_SCRAPE_LIMIT) {
+            stats.extend(tracker.scrape(chunk).await?);
+        }
+        Ok(stats)
+    }
+
+    async fn announce_to(&mut self, url: &str, info_hash: &[u8; 20], request: &Request) -> anyhow::Result<Response> {
+        let response = if url.starts_with("udp://") {
+            if !self.udp_trackers.contains_key(url) {
+                let tracker = UdpTracker::new(url).await?;
+                self.udp_trackers.insert(url.to_string(), tracker);
+            }
+            let tracker = self.udp_trackers.get_mut(url).expect("inserted above");
+            tracker.announce(info_hash, request).await?
+        } else {
+            let mut request = request.clone();
+            request.trackerid = self.tracker_ids.get(url).cloned();
+            announce(url, info_hash, &request).await?
+        };
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_ids.insert(url.to_string(), tracker_id.clone());
+        }
+        Ok(response)
+    }
+}
+
+/// A UDP scrape packet has room for about 74 info hashes
+const UDP_SCRAPE_LIMIT: usize = 70;
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    /// keyed by the raw 20 byte info hash
+    #[serde(default)]
+    files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+/// The scrape URL of an HTTP tracker, only trackers whose last path segment
+/// starts with `announce` support scraping
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+    let (base, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    let mut url = format!("{base}/scrape{rest}");
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    Some(url)
+}
+
+/// Scrapes a single tracker, stats come back in the order of `info_hashes`.
+/// Hashes the tracker does not know about are reported as all zeros.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+    if url.starts_with("udp://") {
+        let mut tracker = UdpTracker::new(url).await?;
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(UDP_SCRAPE_LIMIT) {
+            stats.extend(tracker.scrape(chunk).await?);
+        }
+        return Ok(stats);
+    }
+    let scrape = scrape_url(url).with_context(|| format!("{url} does not support scrape"))?;
+    let query = info_hashes
+        .iter()
+        .map(|hash| format!("info_hash={}", encode_binary(hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if scrape.contains('?') { '&' } else { '?' };
+    let response = reqwest::get(format!("{scrape}{separator}{query}")).await.context("Query Tracker")?;
+    let response = response.bytes().await.context("Fetch scrape response")?;
+    let response: ScrapeResponse = serde_bencode::from_bytes(&response).map_err(TrackerError::from)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(TrackerError::Failure(reason).into());
+    }
+    Ok(info_hashes
+        .iter()
+        .map(|hash| response.files.get(serde_bytes::Bytes::new(hash)).copied().unwrap_or_default())
+        .collect())
+}
+
+fn merge(mut into: Response, from: Response) -> Response {
+    into.interval = into.interval.min(from.interval);
+    into.min_interval = into.min_interval.max(from.min_interval);
+    into.complete = into.complete.max(from.complete);
+    into.incomplete = into.incomplete.max(from.incomplete);
+    into.warning_message = into.warning_message.or(from.warning_message);
+    let mut seen: HashSet<_> = into.peer_addrs().into_iter().collect();
+    into.peers.0.extend(from.peers.0.into_iter().filter(|peer| seen.insert(*peer)));
+    into.peers6.0.extend(from.peers6.0.into_iter().filter(|peer| seen.insert(*peer)));
+    into
+}
+
+/// Transfer counters reported to trackers, updated by the download as pieces verify
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub uploaded: AtomicUsize,
+    pub downloaded: AtomicUsize,
+    pub left: AtomicUsize,
+}
+
+impl TransferStats {
+    pub fn new(left: usize) -> Self {
+        Self { left: AtomicUsize::new(left), ..Default::default() }
+    }
+
+    /// Counts a verified piece as downloaded
+    pub fn piece_verified(&self, piece_size: usize) {
+        self.downloaded.fetch_add(piece_size, Ordering::Relaxed);
+        let _ = self.left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+            Some(left.saturating_sub(piece_size))
+        });
+    }
+
+    pub fn uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Announce state for one torrent: counters, `key`, and the intervals the trackers asked for
+#[derive(Debug)]
+pub struct TrackerSession {
+    pub trackers: TrackerList,
+    info_hash: [u8; 20],
+    peer_id: String,
+    port: u16,
+    key: String,
+    pub stats: Arc<TransferStats>,
+    interval: Duration,
+    min_interval: Duration,
+    events: Events,
+}
+
+impl TrackerSession {
+    pub fn new(trackers: TrackerList, info_hash: [u8; 20], peer_id: String, port: u16, stats: Arc<TransferStats>) -> Self {
+        Self {
+            trackers,
+            info_hash,
+            peer_id,
+            port,
+            key: format!("{:08x}", rand::random::<u32>()),
+            stats,
+            interval: Duration::from_secs(30 * 60),
+            min_interval: Duration::ZERO,
+            events: Events::default(),
+        }
+    }
+
+    /// Reports every answered announce to `events`
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Announces with the current counters and remembers the re-announce interval
+    pub async fn announce(&mut self, event: Option<Event>) -> anyhow::Result<Response> {
+        let request = Request {
+            peer_id: self.peer_id.clone(),
+            port: self.port,
+            uploaded: self.stats.uploaded.load(Ordering::Relaxed),
+            downloaded: self.stats.downloaded.load(Ordering::Relaxed),
+            left: self.stats.left.load(Ordering::Relaxed),
+            compact: 1,
+            event,
+            key: Some(self.key.clone()),
+            trackerid: None,
+        };
+        let response = self.trackers.announce(&self.info_hash, &request).await?;
+        self.interval = Duration::from_secs(response.interval as u64).max(MIN_ANNOUNCE_INTERVAL);
+        self.min_interval = Duration::from_secs(response.min_interval.unwrap_or_default() as u64);
+        self.events.send(DownloadEvent::TrackerAnnounced { peers: response.peer_addrs().len() });
+        Ok(response)
+    }
+
+    /// Time until the next regular announce, never shorter than `min interval` or `MIN_ANNOUNCE_INTERVAL`
+    pub fn next_announce(&self) -> Duration {
+        self.interval.max(self.min_interval)
+    }
+
+    /// Re-announces in the background until stopped, peers from every answer are forwarded
+    pub fn spawn(mut self) -> TrackerHandle {
+        let (commands, mut command_rx) = mpsc::unbounded_channel();
+        let (peers_tx, peers) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = tokio::time::sleep(self.next_announce()) => None,
+                    command = command_rx.recv() => Some(command.unwrap_or(Event::Stopped)),
+                };
+                let response = self.announce(event).await;
+                if event == Some(Event::Stopped) {
+                    break;
+                }
+                if let Ok(response) = response {
+                    let _ = peers_tx.send(response.peer_addrs());
+                }
+            }
+        });
+        TrackerHandle { commands, task, peers }
+    }
+}
+
+/// Controls a spawned TrackerSession
+#[derive(Debug)]
+pub struct TrackerHandle {
+    commands: mpsc::UnboundedSender<Event>,
+    task: JoinHandle<()>,
+    /// peers from each re-announce
+    pub peers: mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+}
+
+impl TrackerHandle {
+    /// Sends `completed`, call once the last piece verified
+    pub fn completed(&self) {
+        let _ = self.commands.send(Event::Completed);
+    }
+
+    /// Sends `stopped` and waits for it to go out
+    pub async fn stop(self) {
+        let _ = self.commands.send(Event::Stopped);
+        let _ = self.task.await;
+    }
+}
+
+/// Announces to a single tracker, `udp://` URLs go through the BEP 15 client
+pub async fn announce(url: &str, info_hash: &[u8; 20], request: &Request) -> anyhow::Result<Response> {
+    if url.starts_with("udp://") {
+        return UdpTracker::new(url).await?.announce(info_hash, request).await;
+    }
+    let encoded_info_hash = encode_binary(info_hash).into_owned();
+    let header = serde_urlencoded::to_string(request).context("Serder Url Encoding")?;
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{url}{separator}{header}&info_hash={encoded_info_hash}");
+    let response = reqwest::get(url).await.context("Query Tracker")?;
+    let response = response.bytes().await.context("Fetch tracker response")?;
+    Ok(Response::from_bytes(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    /// Minimal HTTP tracker answering every request with `body`
+    async fn stand_in_tracker(body: Vec<u8>) -> String {
+        recording_tracker(body).await.0
+    }
+
+    /// Same as stand_in_tracker but also hands back every request line it saw
+    async fn recording_tracker(body: Vec<u8>) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 2048];
+                let len = socket.read(&mut buf).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&buf[..len]);
+                log.lock().unwrap().push(request.lines().next().unwrap_or_default().to_string());
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = socket.write_all(header.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            }
+        });
+        (format!("http://{addr}/announce"), requests)
+    }
+
+    fn request() -> Request {
+        Request {
+            peer_id: "123456789abcdefghijk".to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            compact: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_merge() {
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/announce", listener.local_addr().unwrap())
+        };
+        let first = stand_in_tracker(b"d8:intervali900e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x7f\x00\x00\x02\x1a\xe1e".to_vec()).await;
+        let second = stand_in_tracker(b"d8:intervali60e5:peers12:\x7f\x00\x00\x02\x1a\xe1\x7f\x00\x00\x03\x1a\xe1e".to_vec()).await;
+
+        let mut list = TrackerList {
+            tiers: vec![vec![dead, first.clone()], vec![second]],
+            ..Default::default()
+        };
+        let response = list.announce(&[0u8; 20], &request()).await.expect("A tracker answered");
+        assert_eq!(response.interval, 60);
+        assert_eq!(response.peers.0.len(), 3);
+        assert_eq!(list.tiers[0][0], first);
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() {
+        let (url, requests) = recording_tracker(
+            b"d8:intervali1e12:min intervali0e10:tracker id3:abc5:peers6:\x7f\x00\x00\x01\x1a\xe1e".to_vec(),
+        )
+        .await;
+        let stats = Arc::new(TransferStats::new(100));
+        let mut session = TrackerSession::new(TrackerList::new(vec![vec![url]]), [0u8; 20], "123456789abcdefghijk".to_string(), 6881, stats.clone());
+        session.announce(Some(Event::Started)).await.expect("Tracker answered");
+        // the tracker asked for a second, far too short
+        assert_eq!(session.next_announce(), MIN_ANNOUNCE_INTERVAL);
+
+        // a regular announce has to happen while the test runs
+        session.interval = Duration::from_millis(500);
+
+        let mut tracker = session.spawn();
+        let peers = tracker.peers.recv().await.expect("Re-announced");
+        assert_eq!(peers.len(), 1);
+        stats.piece_verified(100);
+        tracker.completed();
+        tracker.stop().await;
+
+        let requests = requests.lock().unwrap();
+        let started = &requests[0];
+        assert!(started.contains("event=started") && started.contains("left=100"));
+        assert!(!started.contains("trackerid"));
+        let key = started.split('&').find(|p| p.starts_with("key=")).unwrap();
+        let regular = &requests[1];
+        assert!(!regular.contains("event=") && regular.contains("trackerid=abc") && regular.contains(key));
+        let completed = requests.iter().find(|r| r.contains("event=completed")).expect("Sent completed");
+        assert!(completed.contains("downloaded=100") && completed.contains("left=0"));
+        assert!(requests.last().unwrap().contains("event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn test_http_scrape() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[1u8; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let (url, requests) = recording_tracker(body).await;
+
+        let stats = scrape(&url, &[[1u8; 20], [2u8; 20]]).await.expect("Scrape failed");
+        assert_eq!(stats[0], ScrapeStats { complete: 5, downloaded: 50, incomplete: 10 });
+        assert_eq!(stats[1], ScrapeStats::default());
+        let request = &requests.lock().unwrap()[0];
+        assert!(request.starts_with("GET /scrape?info_hash=%01%01") && request.contains("&info_hash=%02%02"));
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(scrape_url("http://example.com/announce").as_deref(), Some("http://example.com/scrape"));
+        assert_eq!(scrape_url("http://example.com/x/announce.php?k=a/b").as_deref(), Some("http://example.com/x/scrape.php?k=a/b"));
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_tiers_keep_order_and_promote() {
+        let mut list = TrackerList::new(vec![
+            vec!["a".into(), "b".into(), "c".into()],
+            vec![],
+            vec!["d".into()],
+        ]);
+        assert_eq!(list.tiers.len(), 2);
+        assert_eq!(list.tiers[1], vec!["d".to_string()]);
+
+        let last = list.tiers[0][2].clone();
+        list.promote(0, 2);
+        assert_eq!(list.tiers[0][0], last);
+        assert_eq!(list.tiers[0].len(), 3);
+    }
+}
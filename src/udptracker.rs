@@ -0,0 +1,311 @@
+//! UDP tracker protocol (BEP 15)
+
+use crate::httprequest::{compact_v4, compact_v6, Event, Peers, Peers6, Request, Response, TrackerError};
+use anyhow::Context;
+use serde::Deserialize;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// A connection ID may be used for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// BEP 15 allows 8, a dead tracker would then hold up an announce for about two hours
+const MAX_RETRIES: u32 = 2;
+
+/// Swarm health for one info hash as reported by a scrape
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct ScrapeStats {
+    /// peers with the whole torrent
+    pub complete: u32,
+    /// number of times the torrent was fully downloaded
+    pub downloaded: u32,
+    /// peers still downloading
+    pub incomplete: u32,
+}
+
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    /// resolved tracker address
+    pub addr: SocketAddr,
+    connection: Option<(u64, Instant)>,
+    /// timeout of the first attempt, doubled on every retransmission (15 * 2 ^ n seconds)
+    pub base_timeout: Duration,
+    /// retransmissions after the first attempt before giving up
+    pub max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port[/announce]` URL and binds a local socket for it
+    pub async fn new(url: &str) -> anyhow::Result<Self> {
+        let host = url
+            .strip_prefix("udp://")
+            .context("Not a udp:// tracker URL")?
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        let addr = tokio::net::lookup_host(host)
+            .await
+            .context("Resolving tracker host")?
+            .next()
+            .context("Tracker host has no address")?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await.context("Binding UDP socket")?;
+        Ok(Self {
+            socket,
+            addr,
+            connection: None,
+            base_timeout: Duration::from_secs(15),
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    pub async fn announce(&mut self, info_hash: &[u8; 20], request: &Request) -> anyhow::Result<Response> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(98);
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(info_hash);
+        packet.extend_from_slice(&peer_id_bytes(&request.peer_id));
+        packet.extend_from_slice(&(request.downloaded as u64).to_be_bytes());
+        packet.extend_from_slice(&(request.left as u64).to_be_bytes());
+        packet.extend_from_slice(&(request.uploaded as u64).to_be_bytes());
+        let event: u32 = match request.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        packet.extend_from_slice(&event.to_be_bytes());
+        // ip address: let the tracker use the sender's
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        let key = request
+            .key
+            .as_deref()
+            .and_then(|key| u32::from_str_radix(key, 16).ok())
+            .unwrap_or_default();
+        packet.extend_from_slice(&key.to_be_bytes());
+        // num_want: tracker default
+        packet.extend_from_slice(&(-1i32).to_be_bytes());
+        packet.extend_from_slice(&request.port.to_be_bytes());
+
+        let reply = self.round_trip(&packet, ACTION_ANNOUNCE, transaction_id).await?;
+        anyhow::ensure!(reply.len() >= 20, "Announce reply too short");
+        let interval = read_u32(&reply, 8) as usize;
+        let leechers = read_u32(&reply, 12) as usize;
+        let seeders = read_u32(&reply, 16) as usize;
+        // peers come in the address family the announce was sent over
+        let (peers, peers6) = if self.addr.is_ipv6() {
+            (Vec::new(), compact_v6(&reply[20..]))
+        } else {
+            (compact_v4(&reply[20..]), Vec::new())
+        };
+        Ok(Response {
+            interval,
+            min_interval: None,
+            tracker_id: None,
+            warning_message: None,
+            complete: Some(seeders),
+            incomplete: Some(leechers),
+            peers: Peers(peers),
+            peers6: Peers6(peers6),
+        })
+    }
+
+    /// Scrapes up to ~70 info hashes in one request
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16 + 20 * info_hashes.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        info_hashes.iter().for_each(|hash| packet.extend_from_slice(hash));
+
+        let reply = self.round_trip(&packet, ACTION_SCRAPE, transaction_id).await?;
+        anyhow::ensure!(
+            reply.len() >= 8 + 12 * info_hashes.len(),
+            "Scrape reply too short"
+        );
+        Ok(reply[8..]
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|chunk| ScrapeStats {
+                complete: read_u32(chunk, 0),
+                downloaded: read_u32(chunk, 4),
+                incomplete: read_u32(chunk, 8),
+            })
+            .collect())
+    }
+
+    /// Cached connection ID, a new one is requested once it is older than a minute
+    async fn connection_id(&mut self) -> anyhow::Result<u64> {
+        if let Some((id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(id);
+            }
+        }
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        let reply = self.round_trip(&packet, ACTION_CONNECT, transaction_id).await?;
+        anyhow::ensure!(reply.len() >= 16, "Connect reply too short");
+        let id = u64::from_be_bytes(reply[8..16].try_into().expect("slice length not 8"));
+        self.connection = Some((id, Instant::now()));
+        Ok(id)
+    }
+
+    /// Sends `packet` until a reply with our transaction ID arrives, waiting
+    /// base_timeout * 2 ^ n before the n-th retransmission
+    async fn round_trip(&self, packet: &[u8], action: u32, transaction_id: u32) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0u8; 65536];
+        for attempt in 0..=self.max_retries {
+            self.socket
+                .send_to(packet, self.addr)
+                .await
+                .context("Sending to tracker")?;
+            let deadline = tokio::time::Instant::now() + self.base_timeout * 2u32.pow(attempt);
+            while let Ok(received) = tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await {
+                let (len, from) = received.context("Receiving from tracker")?;
+                if from != self.addr || len < 8 || read_u32(&buf, 4) != transaction_id {
+                    continue;
+                }
+                match read_u32(&buf, 0) {
+                    ACTION_ERROR => {
+                        let reason = String::from_utf8_lossy(&buf[8..len]).into_owned();
+                        return Err(TrackerError::Failure(reason).into());
+                    }
+                    a if a == action => return Ok(buf[..len].to_vec()),
+                    a => anyhow::bail!("Unexpected tracker action {}", a),
+                }
+            }
+        }
+        anyhow::bail!("Tracker did not answer")
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().expect("slice length not 4"))
+}
+
+fn peer_id_bytes(peer_id: &str) -> [u8; 20] {
+    let mut bytes = [0u8; 20];
+    let len = peer_id.len().min(20);
+    bytes[..len].copy_from_slice(&peer_id.as_bytes()[..len]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Stand-in tracker that drops the first `drop_first` packets it receives
+    async fn stand_in_tracker(drop_first: usize) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let mut seen = 0;
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                seen += 1;
+                if seen <= drop_first {
+                    continue;
+                }
+                let action = read_u32(&buf, 8);
+                let transaction_id = &buf[12..16];
+                let mut reply = Vec::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(u64::from_be_bytes(buf[0..8].try_into().unwrap()), PROTOCOL_ID);
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        reply.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        reply.extend_from_slice(transaction_id);
+                        reply.extend_from_slice(&42u64.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(len, 98);
+                        assert_eq!(u64::from_be_bytes(buf[0..8].try_into().unwrap()), 42);
+                        reply.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        reply.extend_from_slice(transaction_id);
+                        reply.extend_from_slice(&1800u32.to_be_bytes());
+                        reply.extend_from_slice(&1u32.to_be_bytes());
+                        reply.extend_from_slice(&2u32.to_be_bytes());
+                        reply.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                    }
+                    ACTION_SCRAPE => {
+                        reply.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                        reply.extend_from_slice(transaction_id);
+                        for _ in 0..(len - 16) / 20 {
+                            reply.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 9, 0, 0, 0, 3]);
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        (url, connects)
+    }
+
+    fn request() -> Request {
+        Request {
+            peer_id: "123456789abcdefghijk".to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 10,
+            compact: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape_reuse_connection_id() {
+        let (url, connects) = stand_in_tracker(0).await;
+        let mut tracker = UdpTracker::new(&url).await.expect("Valid URL");
+        let response = tracker.announce(&[1u8; 20], &request()).await.expect("Announce failed");
+        assert_eq!(response.interval, 1800);
+        assert_eq!((response.complete, response.incomplete), (Some(2), Some(1)));
+        assert_eq!(response.peers.0, vec!["10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6882".parse().unwrap()]);
+
+        let stats = tracker.scrape(&[[1u8; 20], [2u8; 20]]).await.expect("Scrape failed");
+        assert_eq!(stats, vec![ScrapeStats { complete: 5, downloaded: 9, incomplete: 3 }; 2]);
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retransmits_after_timeout() {
+        let (url, _) = stand_in_tracker(2).await;
+        let mut tracker = UdpTracker::new(&url).await.expect("Valid URL");
+        tracker.base_timeout = Duration::from_millis(20);
+        let response = tracker.announce(&[1u8; 20], &request()).await.expect("Announce failed");
+        assert_eq!(response.peers.0.len(), 2);
+
+        tracker.max_retries = 0;
+        tracker.connection = None;
+        let (silent, _) = stand_in_tracker(usize::MAX).await;
+        tracker.addr = silent.strip_prefix("udp://").unwrap().trim_end_matches("/announce").parse().unwrap();
+        assert!(tracker.announce(&[1u8; 20], &request()).await.is_err());
+    }
+}
@@ -1,11 +1,26 @@
 use crate::{
     handshake::Handshake,
     magnet::Magnet,
-    message::{Message, MessageFramer, MessageTag, Payload, requestpayload::{ReceivePayload, RequestPayload}},
-    httprequest::{Request, Response},
+    message::{Message, MessageFramer, MessageTag, Payload, requestpayload::ReceivePayload},
+    httprequest::{Event, Request, Response},
+    constant,
+    choker::Choker,
+    endgame::SharedBlocks,
+    filepriority::FilePriorities,
+    events::{DownloadEvent, Events},
+    listener::{Listener, LISTEN_PORTS},
+    peerconnection::PeerConnection,
+    ratelimit::Limits,
+    requestwindow::{RequestWindow, BLOCK_SIZE},
+    resume::Resume,
+    seed::Seeder,
+    storage::Storage,
+    swarm::{Swarm, MAX_STRIKES},
     torrent::Torrent,
+    tracker::{self, TrackerList, TrackerSession, TransferStats},
+    udptracker::ScrapeStats,
     extension::{
-        extensionhandshake::ExtensionHandshake, 
+        extensionhandshake::{ExtensionHandshake, M}, 
         extensionmetadata::{ExtensionMetadata, MetaData}, 
         extensionpayload::{ExtensionPayload, ExtensionType}
     }, 
@@ -13,122 +28,147 @@ use crate::{
 use anyhow::{Context};
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use sha1::{Digest, Sha1};
-use std::{fs, net::SocketAddrV4};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
 };
+use thiserror::Error;
 use tokio_util::codec::Framed;
-use urlencoding::encode_binary;
-
-//string have 4:home
-//numbers have i-3e
-//list [a,b] = l1:a1:be
-//dictionary {'cow': 'moo', 'spam': 'eggs'} = d3:cow3:moo4:spam4:eggse
-pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
-    match &encoded_value.chars().next() {
-        Some('i') => {
-            if let Some((digit, rest)) =
-                encoded_value
-                    .split_at(1)
-                    .1
-                    .split_once('e')
-                    .and_then(|(digits, rest)| {
-                        let n = digits.parse::<i64>().ok()?;
-                        Some((n, rest))
-                    })
-            {
-                return (digit.into(), rest);
-            }
-        }
-        Some('l') => {
-            let mut res = Vec::new();
-            let mut rem = encoded_value.split_at(1).1;
-            while let Some(c) = rem.chars().next() {
-                if c == 'e' {
-                    break;
-                }
-                let (result, rest) = decode_bencoded_value(rem);
-                res.push(result);
-                rem = rest;
-            }
-            return (res.into(), &rem[1..]);
-        }
-        Some('d') => {
-            let mut dict = serde_json::Map::new();
-            let mut rem = encoded_value.split_at(1).1;
-            while !rem.is_empty() && !rem.starts_with('e') {
-                let (key, rest) = decode_bencoded_value(rem);
-                let (value, rest) = decode_bencoded_value(rest);
-                if let serde_json::Value::String(key_string) = key {
-                    dict.insert(key_string, value);
-                } else {
-                    panic!("Dict keys should be strings")
-                }
-                rem = rest;
-            }
-            return (dict.into(), &rem[1..]);
-        }
-        Some('0'..='9') => {
-            if let Some((len, rest)) = encoded_value.split_once(':') {
-                if let Ok(len) = len.parse::<usize>() {
-                    return (rest[..len].to_string().into(), &rest[len..]);
-                }
-            }
-        }
-        _ => {}
-    }
-    panic!("Unhandled encoded value: {}", encoded_value)
-}
+
+/// A peer that chokes us mid piece gets this long to unchoke us again
+const CHOKED_TIMEOUT: Duration = Duration::from_secs(30);
+/// An unchoking peer that holds our requests this long without sending a block has stalled
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A downloaded piece did not match its hash in the info dictionary
+#[derive(Debug, Error)]
+#[error("Piece {0} failed the hash check")]
+pub struct HashMismatch(pub usize);
+
+/// The peer choked us during a piece and did not unchoke us in time
+#[derive(Debug, Error)]
+#[error("Peer choked us during piece {0}")]
+pub struct Choked(pub usize);
+
+/// The peer kept us unchoked but sent no block of the piece for `REQUEST_TIMEOUT`
+#[derive(Debug, Error)]
+#[error("Peer stopped sending blocks of piece {0}")]
+pub struct Stalled(pub usize);
 
 /// This file will contain all the helper function used in main
-pub fn read_and_deserialize_torrent(info: &String) -> anyhow::Result<Torrent> {
+pub fn read_and_deserialize_torrent(info: &str) -> anyhow::Result<Torrent> {
     let content = fs::read(info).context("Read file")?;
-    let tor: Torrent = serde_bencode::from_bytes(&content).context("Convert file to a struct")?;
-    Ok(tor)
+    Torrent::from_bytes(&content)
 }
 
-pub async fn get_peers_from_tracker_url(info: &String) -> anyhow::Result<(Response, Torrent)> {
+pub async fn get_peers_from_tracker_url(info: &str) -> anyhow::Result<(Response, Torrent)> {
     let tor: Torrent =
         read_and_deserialize_torrent(info).context("Unable to read and deserialize")?;
     let info_hash = tor.info_hash();
-    let left = tor.info.length;
-    let encoded_info_hash = encode_binary(&info_hash).into_owned();
+    let left = tor.info.total_length();
     let request_body = Request {
-        peer_id: "123456789abcdefghijk".to_string(),
+        peer_id: constant::get_peer_id_string(),
         port: 6881,
         downloaded: 0,
         uploaded: 0,
-        left: left,
+        left,
         compact: 1,
+        ..Default::default()
     };
-    let header = serde_urlencoded::to_string(&request_body).context("Serder Url Encoding")?;
-    let url = format!(
-        "{}?{}&info_hash={}",
-        tor.announce, header, encoded_info_hash
-    );
-    let response = reqwest::get(url).await.context("Query Tracker")?;
-    let response = response.bytes().await.context("Fetch tracker response")?;
-    let response: Response =
-        serde_bencode::from_bytes(&response).context("Decoding response to response struct")?;
+    let response = TrackerList::from_torrent(&tor)
+        .announce(&info_hash, &request_body)
+        .await
+        .context("Announce to trackers")?;
     Ok((response, tor))
 }
 
+/// Info hash and tracker tiers of a .torrent path or a magnet link
+fn scrape_target(input: &str) -> anyhow::Result<([u8; 20], Vec<Vec<String>>)> {
+    if input.starts_with("magnet:") {
+        let magnet = Magnet::new(input).context("Parsing failed")?;
+        return Ok((magnet.info_hash_to_slice(), vec![vec![magnet.url]]));
+    }
+    let tor = read_and_deserialize_torrent(input).context("Unable to read and deserialize")?;
+    let tiers = TrackerList::from_torrent(&tor).tiers;
+    Ok((tor.info_hash(), tiers))
+}
+
+/// Tracker tiers and the info hashes scraped from them in one request
+type ScrapeGroup<'a> = (&'a [Vec<String>], Vec<[u8; 20]>);
+
+/// Targets with the same set of trackers, in whatever tiers or order, with the tiers of the first of them
+fn scrape_groups(targets: &[([u8; 20], Vec<Vec<String>>)]) -> Vec<ScrapeGroup<'_>> {
+    let mut groups: HashMap<BTreeSet<&str>, ScrapeGroup> = HashMap::new();
+    for (info_hash, tiers) in targets {
+        let urls = tiers.iter().flatten().map(String::as_str).collect();
+        groups.entry(urls).or_insert_with(|| (tiers, Vec::new())).1.push(*info_hash);
+    }
+    groups.into_values().collect()
+}
+
+/// Scrapes the swarm of every torrent path or magnet link in `inputs`.
+/// Inputs sharing the same trackers are scraped in one request, results keep the input order.
+/// When every tracker of a group fails only the inputs of that group get the error.
+pub async fn scrape_swarms(inputs: &[String]) -> anyhow::Result<Vec<([u8; 20], anyhow::Result<ScrapeStats>)>> {
+    let targets = inputs
+        .iter()
+        .map(|input| scrape_target(input))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut results: HashMap<[u8; 20], Result<ScrapeStats, String>> = HashMap::new();
+    for (tiers, hashes) in scrape_groups(&targets) {
+        match TrackerList::new(tiers.to_vec()).scrape(&hashes).await {
+            Ok(stats) => results.extend(hashes.into_iter().zip(stats.into_iter().map(Ok))),
+            Err(e) => results.extend(hashes.into_iter().map(|info_hash| (info_hash, Err(format!("{e:#}"))))),
+        }
+    }
+    Ok(targets
+        .iter()
+        .map(|(info_hash, _)| {
+            let stats = match results.get(info_hash) {
+                Some(Ok(stats)) => Ok(*stats),
+                Some(Err(e)) => Err(anyhow::anyhow!("{e}")),
+                None => Ok(ScrapeStats::default()),
+            };
+            (*info_hash, stats)
+        })
+        .collect())
+}
+
 pub async fn establish_handshake(
     info_hash: [u8; 20],
-    peer: &SocketAddrV4,
+    peer: &SocketAddr,
     reserved: [u8; 8],
 ) -> anyhow::Result<(TcpStream, String)> {
     let mut tcp_stream = TcpStream::connect(peer)
         .await
         .context("TCP connection to peer")?;
-    let peer_id: [u8; 20] = *b"ABCDEFGHIJKLMNOPQRST"; // exactly 20 bytes
+    let handshake = exchange_handshake(&mut tcp_stream, info_hash, reserved).await?;
+    Ok((tcp_stream, hex::encode(handshake.peer_id)))
+}
+
+/// Sends our handshake on a fresh connection and reads the peer's, which has to be
+/// a BitTorrent handshake for the same torrent
+pub async fn exchange_handshake(
+    tcp_stream: &mut TcpStream,
+    info_hash: [u8; 20],
+    reserved: [u8; 8],
+) -> anyhow::Result<Handshake> {
+    let peer_id: [u8; 20] = constant::get_peer_id(); // exactly 20 bytes
     let handshake_message = Handshake {
         protocol_name: *b"BitTorrent protocol",
         protocol_length: 19,
-        reserved: reserved,
-        info_hash: info_hash,
-        peer_id: peer_id,
+        reserved,
+        info_hash,
+        peer_id,
     };
     tcp_stream
         .write_all(&handshake_message.as_bytes())
@@ -139,197 +179,524 @@ pub async fn establish_handshake(
         .read_exact(&mut res)
         .await
         .context("Read from peers")?;
-    let peer_id = hex::encode(&res[48..]);
-    Ok((tcp_stream, peer_id))
+    anyhow::ensure!(
+        res[0] == 19 && &res[1..20] == b"BitTorrent protocol",
+        "Not a BitTorrent handshake"
+    );
+    anyhow::ensure!(res[28..48] == info_hash, "Peer answered for another torrent");
+    Ok(Handshake::from_bytes(&res))
 }
 
 pub async fn establish_handshake_and_download(
-    output: &String,
-    info: &String,
+    output: &str,
+    info: &str,
+    index: Option<usize>,
+    reserved: [u8; 8],
+    events: Events,
+    limits: Limits,
+    files: Option<&str>,
+) -> anyhow::Result<()> {
+    let tor: Torrent =
+        read_and_deserialize_torrent(info).context("Unable to read and deserialize")?;
+    download_torrent(tor, output, index, reserved, events, limits, files).await
+}
+
+/// Downloads piece `index` of `tor` to `output`, or the whole torrent (the `files` of it)
+/// when there is no index, from every peer its tracker and incoming connections offer
+pub async fn download_torrent(
+    tor: Torrent,
+    output: &str,
     index: Option<usize>,
     reserved: [u8; 8],
+    events: Events,
+    limits: Limits,
+    files: Option<&str>,
 ) -> anyhow::Result<()> {
-    let (response, tor) = get_peers_from_tracker_url(info)
+    let tor = Arc::new(tor);
+    if let Some(index) = index {
+        anyhow::ensure!(index < tor.info.num_pieces(), "No piece {index}, the torrent has {}", tor.info.num_pieces());
+    }
+    let file_entries = tor.info.file_entries()?;
+    let priorities = match files {
+        Some(selector) => FilePriorities::select(file_entries.len(), selector).context("Select files")?,
+        None => FilePriorities::new(file_entries.len()),
+    };
+    // a whole torrent goes straight to disk and picks up where an earlier run stopped
+    let resume = match index {
+        Some(_) => None,
+        None => Some(open_resume(&tor, output, &priorities).await?),
+    };
+    let wanted: Vec<usize> = match index {
+        Some(piece_index) => vec![piece_index],
+        None => priorities.wanted_pieces(&tor.info, &file_entries),
+    };
+    let verified = resume.as_ref().map(|resume| resume.verified()).unwrap_or_default();
+    let length = wanted.iter().map(|piece| tor.info.piece_size(*piece)).sum::<usize>();
+    let left = length - wanted.iter().filter(|piece| verified.has(**piece)).map(|piece| tor.info.piece_size(*piece)).sum::<usize>();
+    let stats = Arc::new(TransferStats::new(left));
+    events.send(DownloadEvent::Started { pieces: wanted.len(), length, left });
+    let (port, incoming) = accept_peers(&tor, reserved, &events).await;
+    let mut session = TrackerSession::new(
+        TrackerList::from_torrent(&tor),
+        tor.info_hash(),
+        constant::get_peer_id_string(),
+        port,
+        stats.clone(),
+    )
+    .with_events(events.clone());
+    let response = session
+        .announce(Some(Event::Started))
         .await
         .context("Unable to get response")?;
-    let peer = &response.peers.0[0];
-    let info_hash = &tor.info_hash();
-    // establish handshake
-    let (tcp_stream, _peer_id) = establish_handshake(*info_hash, peer, reserved)
-        .await
-        .context("Unable to establish handhshake")?;
+    let mut tracker = session.spawn();
+    let mut swarm = Swarm::new(tor.clone(), stats.clone(), reserved)
+        .with_events(events)
+        .with_limits(limits)
+        .with_high_priority(priorities.high_pieces(&tor.info, &file_entries));
+    let (connections, listening) = incoming.unzip();
+    if let Some(connections) = connections {
+        swarm = swarm.with_incoming(connections);
+    }
+    let peers = response.peer_addrs();
+    let res = match resume {
+        // the whole torrent is written piece by piece as it verifies
+        Some(resume) => swarm.download(resume, &wanted, peers, Some(&mut tracker.peers)).await.map(|_| None),
+        None => swarm.download_to_memory(&wanted, peers, Some(&mut tracker.peers)).await.map(Some),
+    };
+    if res.is_ok() && index.is_none() && left > 0 {
+        tracker.completed();
+    }
+    tracker.stop().await;
+    listening.inspect(JoinHandle::abort);
 
-    // open up a bidirectional tcp socket for communication
-    let codec = MessageFramer;
-    let mut tcp_stream = Framed::new(tcp_stream, codec);
-    let message_received = tcp_stream
-        .next()
+    if let Some(piece) = res? {
+        tokio::fs::write(&output, piece)
+            .await
+            .context("write out downloaded piece")?;
+    }
+    Ok(())
+}
+
+/// Creates the files of `tor` at `output`, leaving out skipped ones, and loads the resume
+/// state an earlier run left there
+pub async fn open_resume(tor: &Arc<Torrent>, output: &str, priorities: &FilePriorities) -> anyhow::Result<Arc<Resume>> {
+    let storage = Storage::new(&tor.info, output)?.with_priorities(&tor.info, priorities);
+    // creating the files and checking the recorded pieces both go to disk
+    let tor = tor.clone();
+    let resume = tokio::task::spawn_blocking(move || {
+        storage.preallocate().context("Create output files")?;
+        Resume::open(tor, Arc::new(storage)).context("Load resume state")
+    })
+    .await
+    .context("Load resume state")??;
+    Ok(Arc::new(resume))
+}
+
+/// Seeds the pieces of `data` that match the torrent until interrupted with Ctrl-C,
+/// `choker` holds the upload slots and every peer counts towards `limits`.
+/// How many pieces are seeded is reported to `events`.
+pub async fn seed_torrent(
+    info: &str,
+    data: &str,
+    reserved: [u8; 8],
+    choker: Choker,
+    limits: Limits,
+    events: Events,
+) -> anyhow::Result<()> {
+    let tor = Arc::new(read_and_deserialize_torrent(info).context("Unable to read and deserialize")?);
+    let storage = Arc::new(Storage::new(&tor.info, data)?);
+    let stats = Arc::new(TransferStats::default());
+    let seeder = Seeder::new(tor.clone(), storage, stats.clone())
+        .with_choker(choker)
+        .with_limits(limits);
+    let have = seeder.have();
+    events.send(DownloadEvent::Seeding { have: have.count(), pieces: have.len() });
+    let left = tor.info.total_length() - have.pieces().map(|piece| tor.info.piece_size(piece)).sum::<usize>();
+    stats.left.store(left, std::sync::atomic::Ordering::Relaxed);
+
+    let (port, incoming) = accept_peers(&tor, reserved, &events).await;
+    let (connections, listening) = incoming.unzip();
+    let mut session = TrackerSession::new(
+        TrackerList::from_torrent(&tor),
+        tor.info_hash(),
+        constant::get_peer_id_string(),
+        port,
+        stats,
+    );
+    let response = session
+        .announce(Some(Event::Started))
         .await
-        .expect("Expecting a btifield")
-        .context("Message was invalid")?;
-    assert_eq!(message_received.message_tag, MessageTag::Bitfield);
+        .context("Unable to get response")?;
+    let mut tracker = session.spawn();
+    let res = tokio::select! {
+        res = Arc::new(seeder).seed(response.peer_addrs(), Some(&mut tracker.peers), connections, reserved) => res,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    tracker.stop().await;
+    listening.inspect(JoinHandle::abort);
+    res
+}
 
-    let message_to_send = Message {
-        message_tag: MessageTag::Interested,
-        payload: Payload::SimplePayload(Vec::new()),
+/// Listens on the first free port for peers of `tor`, returns the port to announce and the
+/// incoming connections with the listener's task. Without a free port we only connect out,
+/// which is reported to `events` like failures to accept peers later on.
+async fn accept_peers(
+    tor: &Torrent,
+    reserved: [u8; 8],
+    events: &Events,
+) -> (u16, Option<(mpsc::UnboundedReceiver<PeerConnection>, JoinHandle<()>)>) {
+    let listener = match Listener::bind_default(constant::get_peer_id()).await {
+        Ok(listener) => listener.with_events(events.clone()),
+        Err(e) => {
+            events.send(DownloadEvent::NotListening(format!("{e:#}")));
+            return (*LISTEN_PORTS.start(), None);
+        }
     };
-    let _message_sent = tcp_stream.send(message_to_send).await;
-    //TODO Need a better way of checking this
-    //assert_eq!(message_sent.unwrap(), ());
+    let port = listener.local_addr().map_or(*LISTEN_PORTS.start(), |addr| addr.port());
+    let connections = listener.routes().register(tor.info_hash(), tor.info.num_pieces(), reserved);
+    (port, Some((connections, tokio::spawn(listener.run()))))
+}
 
-    let message_received = tcp_stream
-        .next()
-        .await
-        .expect("Expecting a unchoke")
-        .context("Message was invalid")?;
-    assert_eq!(message_received.message_tag, MessageTag::Unchoke);
-
-    // fetching pieces sequentially
-    // not using any pipelining
-    let mut res: Vec<u8> = Vec::new();
-    if let Some(piece_index) = index {
-        res = fetch_a_piece(&tor, &mut tcp_stream, piece_index)
-            .await
-            .context("Fetch a piece failed")?;
-    } else {
-        res = fetch_all_pieces(&tor, &mut tcp_stream)
-            .await
-            .context("Fetch all piece failed")?;
+/// Requests for the piece after the one being downloaded, sent once every block of the
+/// current piece is requested so the window stays full across the piece boundary
+#[derive(Debug, Default)]
+pub struct Prefetch {
+    piece: Option<usize>,
+    /// blocks not requested yet, in order
+    pending: Vec<usize>,
+    /// begin offset -> (block length, when it was requested)
+    outstanding: HashMap<u32, (usize, Instant)>,
+    /// blocks that arrived before the piece's download started, by begin offset
+    arrived: HashMap<u32, Vec<u8>>,
+}
+
+impl Prefetch {
+    /// Starts requesting `piece` ahead, unless it already is
+    fn start(&mut self, tor: &Torrent, piece: usize) {
+        if self.piece != Some(piece) {
+            let num_of_blocks = tor.info.piece_size(piece).div_ceil(BLOCK_SIZE);
+            *self = Self {
+                piece: Some(piece),
+                pending: (0..num_of_blocks).rev().collect(),
+                ..Self::default()
+            };
+        }
     }
 
-    tokio::fs::write(&output, res)
-        .await
-        .context("write out downloaded piece")?;
-    Ok(())
+    /// Withdraws the requests sent ahead, the piece is not downloaded next after all
+    pub async fn cancel(&mut self, conn: &mut PeerConnection) -> anyhow::Result<()> {
+        if let Some(piece) = self.piece {
+            for (begin, (block_size, _)) in self.outstanding.drain() {
+                conn.cancel(piece, begin, block_size).await?;
+            }
+        }
+        *self = Self::default();
+        Ok(())
+    }
 }
 
+/// Downloads and verifies one piece, keeping up to `window` block requests in flight.
+/// Blocks may arrive in any order and are placed by their `begin` offset.
+/// A piece failing the hash check is downloaded again, the peer gets `MAX_STRIKES` tries.
 pub async fn fetch_a_piece(
     tor: &Torrent,
-    tcp_stream: &mut Framed<TcpStream, MessageFramer>,
+    conn: &mut PeerConnection,
     piece_index: usize,
+    window: &mut RequestWindow,
 ) -> anyhow::Result<Vec<u8>> {
-    let num_of_pieces = *&tor.info.pieces.0.len();
-    let piece_size = if piece_index < num_of_pieces - 1 {
-        *&tor.info.pieces_length
-    } else {
-        &tor.info.length - (&tor.info.pieces_length * (num_of_pieces - 1))
-    };
-    println!(
-        "Piece index = {} and Piece size = {}",
-        piece_index, piece_size
-    );
-    let num_of_blocks = piece_size.div_ceil(16 * 1024);
-    // println!("Number of blocks{}",num_of_blocks);
-    let mut blocks: Vec<u8> = Vec::new();
-    let mut begin = 0;
-    for block in 0..num_of_blocks {
-        let block_size = if block < num_of_blocks - 1 {
-            16 * 1024
-        } else {
-            piece_size - (16 * 1024 * (num_of_blocks - 1))
+    fetch_verified_piece(tor, conn, piece_index, window, &mut Prefetch::default(), None).await
+}
+
+/// Same as fetch_a_piece, but the blocks of `next` are requested through `prefetch` while
+/// the last blocks of this piece are on their way
+async fn fetch_verified_piece(
+    tor: &Torrent,
+    conn: &mut PeerConnection,
+    piece_index: usize,
+    window: &mut RequestWindow,
+    prefetch: &mut Prefetch,
+    next: Option<usize>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut strikes = 0;
+    loop {
+        match fetch_piece(tor, conn, piece_index, window, None, prefetch, next).await {
+            Ok(piece) => return Ok(piece.expect("only shared pieces finish elsewhere")),
+            Err(e) if e.downcast_ref::<HashMismatch>().is_some() && strikes + 1 < MAX_STRIKES => strikes += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Same as fetch_a_piece, but blocks are exchanged through `shared` with every other peer
+/// downloading this piece: blocks they already have are not requested, and requests for
+/// blocks they deliver first are cancelled. None when another peer finished the piece.
+/// The blocks of `next` are requested ahead through `prefetch`, like fetch_all_pieces does.
+pub async fn fetch_shared_piece(
+    tor: &Torrent,
+    conn: &mut PeerConnection,
+    piece_index: usize,
+    window: &mut RequestWindow,
+    shared: &SharedBlocks,
+    prefetch: &mut Prefetch,
+    next: Option<usize>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    fetch_piece(tor, conn, piece_index, window, Some(shared), prefetch, next).await
+}
+
+async fn fetch_piece(
+    tor: &Torrent,
+    conn: &mut PeerConnection,
+    piece_index: usize,
+    window: &mut RequestWindow,
+    shared: Option<&SharedBlocks>,
+    prefetch: &mut Prefetch,
+    next: Option<usize>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    anyhow::ensure!(piece_index < tor.info.num_pieces(), "No piece {piece_index}, the torrent has {}", tor.info.num_pieces());
+    if let Some(next) = next {
+        anyhow::ensure!(next < tor.info.num_pieces(), "No piece {next}, the torrent has {}", tor.info.num_pieces());
+    }
+    let piece_size = tor.info.piece_size(piece_index);
+    let num_of_blocks = piece_size.div_ceil(BLOCK_SIZE);
+    let mut blocks: Vec<u8> = vec![0; piece_size];
+    // blocks not requested yet, in order
+    let mut pending = (0..num_of_blocks).rev().collect::<Vec<_>>();
+    // begin offset -> (block length, when it was requested)
+    let mut outstanding: HashMap<u32, (usize, Instant)> = HashMap::new();
+    let mut received = 0;
+    if prefetch.piece == Some(piece_index) {
+        // this piece was requested ahead during the previous one
+        let ahead = std::mem::take(prefetch);
+        pending = ahead.pending;
+        outstanding = ahead.outstanding;
+        for (begin, block) in ahead.arrived {
+            let begin = begin as usize;
+            blocks[begin..begin + block.len()].copy_from_slice(&block);
+            if let Some(shared) = shared {
+                shared.insert(piece_index, begin as u32, &block, conn.peer_addr()).await;
+            }
+            received += 1;
+        }
+    } else if prefetch.piece.is_some() && prefetch.piece != next {
+        prefetch.cancel(conn).await?;
+    }
+    let mut last_arrival: Option<Instant> = None;
+    // when we give up waiting for an unchoke
+    let mut choked_until = conn.peer_choking.then(|| tokio::time::Instant::now() + CHOKED_TIMEOUT);
+    // the last time a block arrived or the peer unchoked us
+    let mut progress = tokio::time::Instant::now();
+    while received < num_of_blocks {
+        let changed = shared.map(|shared| shared.changed());
+        tokio::pin!(changed);
+        if let (Some(shared), Some(changed)) = (shared, changed.as_mut().as_pin_mut()) {
+            changed.enable();
+            if !shared.is_active(piece_index) {
+                // another peer finished the whole piece
+                for (begin, (block_size, _)) in outstanding.drain() {
+                    conn.cancel(piece_index, begin, block_size).await?;
+                }
+                return Ok(None);
+            }
+            // another peer delivered blocks we asked for
+            let delivered: Vec<u32> = outstanding
+                .iter()
+                .filter(|(&begin, &(block_size, _))| {
+                    let range = begin as usize..begin as usize + block_size;
+                    shared.copy_block(piece_index, begin, &mut blocks[range])
+                })
+                .map(|(&begin, _)| begin)
+                .collect();
+            for begin in delivered {
+                let (block_size, _) = outstanding.remove(&begin).expect("collected from outstanding");
+                conn.cancel(piece_index, begin, block_size).await?;
+                received += 1;
+                progress = tokio::time::Instant::now();
+            }
+        }
+        // a choked peer drops whatever we request
+        while !conn.peer_choking && outstanding.len() + prefetch.outstanding.len() < window.size() {
+            let Some(block) = pending.pop() else {
+                // every block of this piece is requested, the next piece fills the window
+                let Some(next) = next else { break };
+                prefetch.start(tor, next);
+                let Some(block) = prefetch.pending.pop() else { break };
+                let begin = block * BLOCK_SIZE;
+                let block_size = BLOCK_SIZE.min(tor.info.piece_size(next) - begin);
+                conn.request(next, begin as u32, block_size).await.context("Send request")?;
+                prefetch.outstanding.insert(begin as u32, (block_size, Instant::now()));
+                continue;
+            };
+            let begin = block * BLOCK_SIZE;
+            let block_size = BLOCK_SIZE.min(piece_size - begin);
+            let range = begin..begin + block_size;
+            if let Some(shared) = shared {
+                if shared.copy_block(piece_index, begin as u32, &mut blocks[range]) {
+                    received += 1;
+                    continue;
+                }
+            }
+            conn.request(piece_index, begin as u32, block_size).await.context("Send request")?;
+            outstanding.insert(begin as u32, (block_size, Instant::now()));
+        }
+        if received == num_of_blocks {
+            break;
+        }
+
+        let message_received = tokio::select! {
+            message = conn.recv() => message?,
+            _ = async {
+                match changed.as_mut().as_pin_mut() {
+                    Some(changed) => changed.await,
+                    None => std::future::pending().await,
+                }
+            } => continue,
+            _ = async {
+                match choked_until {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => return Err(Choked(piece_index).into()),
+            _ = tokio::time::sleep_until(progress + REQUEST_TIMEOUT), if !conn.peer_choking && !outstanding.is_empty() => {
+                return Err(Stalled(piece_index).into());
+            }
         };
-        // println!("Block index = {} and Block size = {}", block, block_size);
-        let request_message = RequestPayload {
-            index: piece_index as u32,
-            begin: begin,
-            length: block_size as u32,
+        match message_received.message_tag {
+            MessageTag::Piece => {}
+            MessageTag::Choke => {
+                // the peer discarded our requests, they are sent again once it unchokes us
+                pending.extend(outstanding.drain().map(|(begin, _)| begin as usize / BLOCK_SIZE));
+                pending.sort_unstable_by(|a, b| b.cmp(a));
+                prefetch.pending.extend(prefetch.outstanding.drain().map(|(begin, _)| begin as usize / BLOCK_SIZE));
+                prefetch.pending.sort_unstable_by(|a, b| b.cmp(a));
+                choked_until = Some(tokio::time::Instant::now() + CHOKED_TIMEOUT);
+                continue;
+            }
+            MessageTag::Unchoke => {
+                choked_until = None;
+                progress = tokio::time::Instant::now();
+                continue;
+            }
+            // extension messages such as PEX and haves can arrive at any time
+            _ => continue,
+        }
+        let Payload::SimplePayload(mut vector) = message_received.payload else {
+            continue;
         };
-        let payload = request_message.to_vec();
-        let message_to_send = Message {
-            message_tag: MessageTag::Request,
-            payload: Payload::SimplePayload(payload),
+        anyhow::ensure!(vector.len() >= 8, "Piece message too short");
+        let received_payload = ReceivePayload::new(&mut vector);
+        let ahead = prefetch.piece.is_some_and(|piece| received_payload.index == piece as u32);
+        if received_payload.index != piece_index as u32 && !ahead {
+            continue;
+        }
+        let requests = match ahead {
+            true => &mut prefetch.outstanding,
+            false => &mut outstanding,
         };
-        let _message_sent = tcp_stream.send(message_to_send).await;
-        // println!("Sent {:?}", _message_sent);
-        //TODO need a better check
-        //assert_eq!(message_sent.unwrap(), ());
-
-        let message_received = tcp_stream
-            .next()
-            .await
-            .expect("Expexting a unchoke")
-            .context("Message was invalid")?;
-        // println!("received");
-        assert_eq!(message_received.message_tag, MessageTag::Piece);
-        if let Payload::SimplePayload(mut vector) = message_received.payload {
-            assert!(!&vector.is_empty());
-            let received_payload = ReceivePayload::new(&mut vector);
-            assert_eq!(received_payload.index, piece_index as u32);
-            assert_eq!(received_payload.begin, begin);
-            assert!(!received_payload.block.is_empty());
-            begin += 16 * 1024;
-            // store each block
-            blocks.extend_from_slice(&received_payload.block);
-        } else {
-            println!("Extended payload received");
+        // blocks we did not ask for, already have, or cancelled are dropped
+        let Some((block_size, requested)) = requests.remove(&received_payload.begin) else {
+            continue;
+        };
+        anyhow::ensure!(
+            received_payload.block.len() == block_size,
+            "Block at {} has {} bytes, expected {}",
+            received_payload.begin,
+            received_payload.block.len(),
+            block_size
+        );
+        let now = Instant::now();
+        window.record(now - requested, last_arrival.map(|last| now - last));
+        last_arrival = Some(now);
+        progress = tokio::time::Instant::now();
+        if ahead {
+            prefetch.arrived.insert(received_payload.begin, received_payload.block.to_vec());
+            continue;
+        }
+        let begin = received_payload.begin as usize;
+        blocks[begin..begin + block_size].copy_from_slice(&received_payload.block);
+        if let Some(shared) = shared {
+            shared.insert(piece_index, received_payload.begin, &received_payload.block, conn.peer_addr()).await;
         }
+        received += 1;
     }
     // check hash
     let mut hasher = Sha1::new();
     hasher.update(&blocks);
-    let res = hex::encode(hasher.finalize());
-    assert_eq!(res, hex::encode(tor.info.pieces.0[piece_index]));
-    // add to slice
-    // pieces.extend_from_slice(&blocks);
-    // blocks.clear();
-    Ok(blocks)
+    let res: [u8; 20] = hasher.finalize().into();
+    if res != tor.info.pieces.0[piece_index] {
+        return Err(HashMismatch(piece_index).into());
+    }
+    Ok(Some(blocks))
 }
 
+/// Downloads the `wanted` pieces from one peer in the order given, each piece is recorded
+/// in `resume` once it verifies and pieces an earlier run verified are skipped.
+/// Requests for a piece go out while the previous one finishes.
 pub async fn fetch_all_pieces(
     tor: &Torrent,
-    tcp_stream: &mut Framed<TcpStream, MessageFramer>,
-) -> anyhow::Result<Vec<u8>> {
-    let mut pieces: Vec<u8> = Vec::new();
-    let num_of_pieces = *&tor.info.pieces.0.len();
-    println!("THe number of pices is {}", num_of_pieces);
-    for piece in 0..num_of_pieces {
-        let res = fetch_a_piece(&tor, tcp_stream, piece)
+    conn: &mut PeerConnection,
+    window: &mut RequestWindow,
+    stats: &TransferStats,
+    resume: &Resume,
+    events: &Events,
+    wanted: &[usize],
+) -> anyhow::Result<()> {
+    let verified = resume.verified();
+    let missing: Vec<usize> = wanted.iter().copied().filter(|piece| !verified.has(*piece)).collect();
+    let length = wanted.iter().map(|piece| tor.info.piece_size(*piece)).sum();
+    let left = missing.iter().map(|piece| tor.info.piece_size(*piece)).sum();
+    events.send(DownloadEvent::Started { pieces: wanted.len(), length, left });
+    if let Some(peer) = conn.peer_addr() {
+        events.send(DownloadEvent::PeerConnected(peer));
+    }
+    let mut prefetch = Prefetch::default();
+    for (i, &piece) in missing.iter().enumerate() {
+        let res = fetch_verified_piece(tor, conn, piece, window, &mut prefetch, missing.get(i + 1).copied())
             .await
             .context("Fetch a piece failed for index")?;
-        pieces.extend_from_slice(&res);
+        let bytes = res.len();
+        resume.piece_verified(piece, res).await.context("write out downloaded piece")?;
+        stats.piece_verified(bytes);
+        events.send(DownloadEvent::PieceVerified { piece, bytes });
     }
-    Ok(pieces)
+    events.send(DownloadEvent::Completed);
+    Ok(())
 }
 
 pub async fn get_peers_from_magnet(magnet: &Magnet) -> anyhow::Result<Response> {
-    let encoded_info_hash = encode_binary(&magnet.info_hash_to_slice()).into_owned();
     //TODO: the left is unknown so use a non zero value
     let request_body = Request {
-        peer_id: "123456789abcdefghijk".to_string(),
+        peer_id: constant::get_peer_id_string(),
         port: 6881,
         downloaded: 0,
         uploaded: 0,
         left: 1000,
         compact: 1,
+        ..Default::default()
     };
-    let header = serde_urlencoded::to_string(&request_body).context("Serder Url Encoding")?;
-    let url = format!("{}?info_hash={}&{}", &magnet.url, encoded_info_hash, header);
-    let response = reqwest::get(url).await.context("Query Tracker")?;
-    let response = response.bytes().await.context("Fetch tracker response")?;
-    let response: Response =
-        serde_bencode::from_bytes(&response).context("Decoding response to response struct")?;
-    Ok(response)
+    tracker::announce(&magnet.url, &magnet.info_hash_to_slice(), &request_body).await
 }
 
-pub async fn magnet_handshake(magnet: &String) -> anyhow::Result<(ExtensionHandshake, Framed<TcpStream, MessageFramer>)>{
+/// Handshakes with the first peer of the magnet's tracker, returns the peer's extension handshake,
+/// the connection and the hex encoded peer id
+pub async fn magnet_handshake(magnet: &str) -> anyhow::Result<(ExtensionHandshake, Framed<TcpStream, MessageFramer>, String)>{
     let magnet: Magnet = Magnet::new(magnet).context("Parsing failed")?;
     let response = get_peers_from_magnet(&magnet)
         .await
         .context("Failed to get peers")?;
     let info_hash = magnet.info_hash_to_slice();
-    let peer = &response.peers.0[0];
+    let peer = response.peer_addrs().first().copied().context("Tracker returned no peers")?;
     let reserved: [u8; 8] = [0, 0, 0, 0, 0, 16, 0, 0];
 
     let mut tcp_stream = TcpStream::connect(peer)
         .await
         .context("TCP connection to peer")?;
-    let peer_id: [u8; 20] = *b"ABCDEFGHIJKLMNOPQRST"; // exactly 20 bytes
+    let peer_id: [u8; 20] = constant::get_peer_id(); // exactly 20 bytes
     let handshake_message = Handshake {
         protocol_name: *b"BitTorrent protocol",
         protocol_length: 19,
-        reserved: reserved,
-        info_hash: info_hash,
-        peer_id: peer_id,
+        reserved,
+        info_hash,
+        peer_id,
     };
     tcp_stream
         .write_all(&handshake_message.as_bytes())
@@ -343,8 +710,7 @@ pub async fn magnet_handshake(magnet: &String) -> anyhow::Result<(ExtensionHands
     let peer_id = hex::encode(&res[48..]);
     let info_hash_received = &res[28..48];
     let peer_reserved_bit = &res[20..28];
-    assert_eq!(info_hash_received, info_hash, "Info Hash Mismatch");
-    println!("Peer ID: {}", peer_id);
+    anyhow::ensure!(info_hash_received == info_hash, "Info Hash Mismatch");
    
     // send bitfield
     // no need to do for this challenge
@@ -355,47 +721,49 @@ pub async fn magnet_handshake(magnet: &String) -> anyhow::Result<(ExtensionHands
     let _response = tcp_stream
         .next()
         .await
-        .expect("Expecting a bitfield message")
+        .context("Expecting a bitfield message")?
         .context("Failed to get bitfield")?;
     // assert!(!response.payload.is_empty());
-    if peer_reserved_bit[2].eq(&reserved[2]) {
-        let content = fs::read("magnet.file").context("Read file")?;
-        let extension_handshake: ExtensionHandshake = serde_bencode::from_bytes(&content).context("Convert file to a struct")?;
-        let extension_payload = ExtensionPayload { 
-            extension_id: 0, 
-            payload: ExtensionType::ExtensionHandshakeMessage(extension_handshake) 
-        };
-        let extension_handshake_message = Message {
-            message_tag: MessageTag::Extension,
-            payload: Payload::ExtendedPayload(extension_payload),
-        };
-        let _ = tcp_stream.send(extension_handshake_message).await;
+    anyhow::ensure!(peer_reserved_bit[2].eq(&reserved[2]), "Peer does not support extensions");
+    let extension_handshake = ExtensionHandshake::new(M {
+        ut_metadata: constant::get_extension_id(),
+        ut_pex: constant::get_pex_extension_id(),
+    });
+    let extension_payload = ExtensionPayload { 
+        extension_id: 0, 
+        payload: ExtensionType::ExtensionHandshakeMessage(extension_handshake) 
+    };
+    let extension_handshake_message = Message {
+        message_tag: MessageTag::Extension,
+        payload: Payload::ExtendedPayload(extension_payload),
+    };
+    let _ = tcp_stream.send(extension_handshake_message).await;
 
-        //receive extension handshake
+    //receive extension handshake, haves and the like may come first
+    loop {
         let extension_reply = tcp_stream
             .next()
             .await
-            .expect("Expecting extension handshake reply")
+            .context("Expecting extension handshake reply")?
             .context("Failed to get reply message")?;
 
         if let Payload::ExtendedPayload(extension_payload) = extension_reply.payload{
             if let ExtensionType::ExtensionHandshakeMessage(handshake_payload) = extension_payload.payload{
-                return Ok((handshake_payload, tcp_stream));
+                return Ok((handshake_payload, tcp_stream, peer_id));
             }
         }
-        panic!("Invalid Payload Received")
-    } 
-    panic!("Peer does not supprt extension")
+    }
 }
 
-pub async fn get_magnet_metadata(magnet: &String) -> anyhow::Result<(Torrent, Framed<TcpStream, MessageFramer>)>{
-    let parsed_magnet: Magnet = Magnet::new(&magnet).context("Parsing failed")?;
+/// Fetches the info dictionary over ut_metadata, the peer's extension handshake is handed back with the connection
+pub async fn get_magnet_metadata(magnet: &str) -> anyhow::Result<(Torrent, Framed<TcpStream, MessageFramer>, ExtensionHandshake)>{
+    let parsed_magnet: Magnet = Magnet::new(magnet).context("Parsing failed")?;
     // perform extension handshake with peer
-    let (extension_payload, mut tcp_stream) = magnet_handshake(magnet)
+    let (peer_handshake, mut tcp_stream, _) = magnet_handshake(magnet)
         .await
         .context("Failed to receive magnet handshake")?;
 
-    let peer_metadata = extension_payload.m.ut_metadata;
+    let peer_metadata = peer_handshake.m.ut_metadata;
     let extension_metadata_request = ExtensionMetadata::Request(
         MetaData{
             msg_type: 0,
@@ -415,22 +783,196 @@ pub async fn get_magnet_metadata(magnet: &String) -> anyhow::Result<(Torrent, Fr
 
     let _res = tcp_stream.send(extension_metadata_message).await.context("Sending failed");
 
-    let extension_metadata_reply = tcp_stream
-        .next()
-        .await
-        .expect("Expecting extension metadata reply")
-        .context("Failed to get reply message")?;
-
-    if let Payload::ExtendedPayload(extension_payload) = extension_metadata_reply.payload{
-        if let ExtensionType::MetaDataMessage(ExtensionMetadata::Data(_message, info)) = extension_payload.payload{
-          
-            let torrent = Torrent{
-                announce: parsed_magnet.url,
-                info
-            };
-            assert_eq!(hex::encode(&torrent.info_hash()), parsed_magnet.info_hash, "Info hash mismatch");
-            return Ok((torrent, tcp_stream));
+    loop {
+        let extension_metadata_reply = tcp_stream
+            .next()
+            .await
+            .context("Expecting extension metadata reply")?
+            .context("Failed to get reply message")?;
+
+        if let Payload::ExtendedPayload(extension_payload) = extension_metadata_reply.payload{
+            match extension_payload.payload {
+                ExtensionType::MetaDataMessage(ExtensionMetadata::Data(_message, info)) => {
+                    let torrent = Torrent::new(parsed_magnet.url, info);
+                    anyhow::ensure!(hex::encode(torrent.info_hash()) == parsed_magnet.info_hash, "Info hash mismatch");
+                    torrent.info.validate()?;
+                    return Ok((torrent, tcp_stream, peer_handshake));
+                }
+                // peers may gossip other peers before answering
+                ExtensionType::PexMessage(_) => continue,
+                _ => {}
+            }
         }
+        // anything else, e.g. an unchoke or a have, is not what we are waiting for
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::single_file_torrent;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_scrape_groups_fail_alone() {
+        // the same trackers in another order make one group
+        let tiers = |urls: &[&str]| vec![urls.iter().map(|url| url.to_string()).collect::<Vec<_>>()];
+        let targets = vec![([1; 20], tiers(&["a", "b"])), ([2; 20], tiers(&["b", "a"])), ([3; 20], tiers(&["c"]))];
+        let mut groups: Vec<Vec<[u8; 20]>> = scrape_groups(&targets).into_iter().map(|(_, hashes)| hashes).collect();
+        groups.sort();
+        assert_eq!(groups, vec![vec![[1; 20], [2; 20]], vec![[3; 20]]]);
+
+        // one tracker that answers every scrape and one that is gone
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut body = b"d5:filesd20:".to_vec();
+            body.extend_from_slice(&[1; 20]);
+            body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.read(&mut [0u8; 2048]).await;
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = socket.write_all(header.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            }
+        });
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/announce", listener.local_addr().unwrap())
+        };
+        let magnet = |hash: u8, url: &str| format!("magnet:?xt=urn:btih:{}&dn=x&tr={url}", hex::encode([hash; 20]));
+        let swarms = scrape_swarms(&[magnet(2, &dead), magnet(1, &live)]).await.expect("Inputs are valid");
+        assert_eq!(swarms[0].0, [2; 20]);
+        assert!(swarms[0].1.is_err());
+        assert_eq!(swarms[1].0, [1; 20]);
+        assert_eq!(swarms[1].1.as_ref().expect("Scraped").complete, 5);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_blocks_out_of_order() {
+        let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        let tor = single_file_torrent(&data, data.len());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = data.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            let unchoke = Message { message_tag: MessageTag::Unchoke, payload: Payload::SimplePayload(Vec::new()) };
+            peer.send(unchoke).await.unwrap();
+            // all three requests must be in flight before the first block comes back
+            let mut requests = Vec::new();
+            while requests.len() < 3 {
+                let message = peer.next().await.unwrap().unwrap();
+                if let Payload::SimplePayload(payload) = message.payload {
+                    let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                    let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                    requests.push((begin, length));
+                }
+            }
+            for (begin, length) in requests.into_iter().rev() {
+                let mut payload = 0u32.to_be_bytes().to_vec();
+                payload.extend_from_slice(&(begin as u32).to_be_bytes());
+                payload.extend_from_slice(&served[begin..begin + length]);
+                let message = Message { message_tag: MessageTag::Piece, payload: Payload::SimplePayload(payload) };
+                peer.send(message).await.unwrap();
+            }
+        });
+
+        let stream = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let mut conn = PeerConnection::new(stream, 1);
+        let mut window = RequestWindow::default();
+        let piece = fetch_a_piece(&tor, &mut conn, 0, &mut window).await.expect("Fetch failed");
+        assert_eq!(piece, data);
+    }
+
+    #[tokio::test]
+    async fn test_requests_sent_again_after_choke() {
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 7) as u8).collect();
+        let tor = single_file_torrent(&data, data.len());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = data.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            let empty = |message_tag| Message { message_tag, payload: Payload::SimplePayload(Vec::new()) };
+            peer.send(empty(MessageTag::Unchoke)).await.unwrap();
+            // the first round of requests is dropped by choking
+            for _ in 0..3 {
+                peer.next().await.unwrap().unwrap();
+            }
+            peer.send(empty(MessageTag::Choke)).await.unwrap();
+            peer.send(empty(MessageTag::Unchoke)).await.unwrap();
+            while let Some(Ok(message)) = peer.next().await {
+                let Payload::SimplePayload(payload) = message.payload else { continue };
+                let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                let mut reply = payload[0..8].to_vec();
+                reply.extend_from_slice(&served[begin..begin + length]);
+                peer.send(Message { message_tag: MessageTag::Piece, payload: Payload::SimplePayload(reply) }).await.unwrap();
+            }
+        });
+
+        let stream = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let mut conn = PeerConnection::new(stream, 1);
+        let mut window = RequestWindow::default();
+        let piece = tokio::time::timeout(Duration::from_secs(5), fetch_a_piece(&tor, &mut conn, 0, &mut window))
+            .await
+            .expect("Requests were not sent again")
+            .expect("Fetch failed");
+        assert_eq!(piece, data);
+    }
+
+    #[tokio::test]
+    async fn test_requests_flow_across_pieces() {
+        let data: Vec<u8> = (0..4 * BLOCK_SIZE as u32).map(|i| (i % 13) as u8).collect();
+        let piece_length = 2 * BLOCK_SIZE;
+        let tor = single_file_torrent(&data, piece_length);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = data.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, MessageFramer);
+            let unchoke = Message { message_tag: MessageTag::Unchoke, payload: Payload::SimplePayload(Vec::new()) };
+            peer.send(unchoke).await.unwrap();
+            // the last block of piece 0 only goes out once piece 1 is requested
+            let mut withheld = None;
+            while let Some(Ok(message)) = peer.next().await {
+                let Payload::SimplePayload(payload) = message.payload else { continue };
+                let index = u32::from_be_bytes(payload[0..4].try_into().unwrap()) as usize;
+                let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                let mut replies = vec![(index, begin, length)];
+                if index == 0 && begin == BLOCK_SIZE {
+                    withheld = replies.pop();
+                } else if index == 1 {
+                    replies.extend(withheld.take());
+                }
+                for (index, begin, length) in replies {
+                    let mut reply = (index as u32).to_be_bytes().to_vec();
+                    reply.extend_from_slice(&(begin as u32).to_be_bytes());
+                    let offset = index * piece_length + begin;
+                    reply.extend_from_slice(&served[offset..offset + length]);
+                    peer.send(Message { message_tag: MessageTag::Piece, payload: Payload::SimplePayload(reply) }).await.unwrap();
+                }
+            }
+        });
+
+        let stream = Framed::new(TcpStream::connect(addr).await.unwrap(), MessageFramer);
+        let mut conn = PeerConnection::new(stream, 2);
+        let mut window = RequestWindow::fixed(3);
+        let mut prefetch = Prefetch::default();
+        let first = tokio::time::timeout(
+            Duration::from_secs(5),
+            fetch_verified_piece(&tor, &mut conn, 0, &mut window, &mut prefetch, Some(1)),
+        )
+        .await
+        .expect("Piece 1 was not requested before piece 0 finished")
+        .expect("Fetch failed");
+        assert_eq!(first, data[..piece_length]);
+        let second = fetch_verified_piece(&tor, &mut conn, 1, &mut window, &mut prefetch, None).await.expect("Fetch failed");
+        assert_eq!(second, data[piece_length..]);
     }
-    panic!("Unrecognized payload received");
 }